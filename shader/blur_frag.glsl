@@ -0,0 +1,25 @@
+#version 330 core
+
+in vec2 tex;
+
+out vec4 final_color;
+
+uniform sampler2D image;
+// Blur along x if true, otherwise along y
+uniform bool horizontal;
+
+// One side of a 9 tap Gaussian kernel, starting at the center
+const float weights[5] =
+    float[](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+
+void main() {
+  vec2 texel = 1.0 / vec2(textureSize(image, 0));
+  vec2 direction = horizontal ? vec2(texel.x, 0.0) : vec2(0.0, texel.y);
+
+  vec3 color = texture(image, tex).rgb * weights[0];
+  for (int i = 1; i < 5; i++) {
+    color += texture(image, tex + direction * i).rgb * weights[i];
+    color += texture(image, tex - direction * i).rgb * weights[i];
+  }
+  final_color = vec4(color, 1.0);
+}
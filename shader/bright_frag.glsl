@@ -0,0 +1,17 @@
+#version 330 core
+
+in vec2 tex;
+
+out vec4 final_color;
+
+uniform sampler2D scene;
+uniform float threshold;
+
+void main() {
+  // Scale the color by how far its brightest channel goes past the threshold,
+  // so the glow keeps the hue of what's glowing
+  vec3 color = texture(scene, tex).rgb;
+  float brightness = max(color.r, max(color.g, color.b));
+  float excess = max(brightness - threshold, 0.0);
+  final_color = vec4(color * excess / max(brightness, 0.0001), 1.0);
+}
@@ -0,0 +1,14 @@
+#version 330 core
+
+in vec2 tex;
+
+out vec4 final_color;
+
+uniform sampler2D scene;
+uniform sampler2D bloom;
+uniform float intensity;
+
+void main() {
+  vec3 color = texture(scene, tex).rgb + texture(bloom, tex).rgb * intensity;
+  final_color = vec4(color, 1.0);
+}
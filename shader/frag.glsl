@@ -1,24 +1,140 @@
 #version 330 core
 
-in vec3 vert_pos;
-in vec2 vert_tex;
-in vec3 vert_normal;
+in Vertex {
+  vec3 pos;
+  vec2 tex;
+  vec3 normal;
+  vec3 color;
+  vec3 tangent;
+  vec3 bitangent;
+} vert;
 
 out vec4 final_color;
 
 uniform vec3 camera_pos;
+uniform bool unlit;
+
+// Options from an MTL map statement
+struct MapOptions {
+  // Scale (-s) in xy and offset (-o) in zw of the texture coordinates
+  vec4 transform;
+  // -imfchan: -1 for the whole color, 0-2 for red, green or blue, 3 for alpha and
+  // 4 for luminance
+  int channel;
+  // -mm: base and gain
+  vec2 base_gain;
+};
 
 struct Material {
   vec3 ambient;
   vec3 diffuse;
   vec3 specular;
   float shininess;
+  vec3 emissive;
+  // 0 is flat color, 1 is diffuse only and 2 and above are Blinn-Phong
+  int illum;
   sampler2D ambient_map;
   sampler2D diffuse_map;
   sampler2D specular_map;
+  sampler2D bump_map;
+  MapOptions ambient_options;
+  MapOptions diffuse_options;
+  MapOptions specular_options;
+  MapOptions bump_options;
+  // 0 for no bump map, 1 for a tangent-space normal map and 2 for a height map
+  int bump_mode;
+  // -bm of the bump map
+  float bump_multiplier;
+  // Shade with the metallic-roughness model instead of Blinn-Phong
+  bool pbr;
+  float roughness;
+  float metallic;
+  float sheen;
+  float clearcoat;
+  float clearcoat_roughness;
+  float anisotropy;
+  sampler2D roughness_map;
+  sampler2D metallic_map;
+  MapOptions roughness_options;
+  MapOptions metallic_options;
+  // d, or 1 - Tr
+  float opacity;
+  // 0 is opaque, 1 discards fragments below ALPHA_CUTOFF and 2 blends
+  int alpha_mode;
+  sampler2D alpha_map;
+  MapOptions alpha_options;
+  sampler2D emissive_map;
+  MapOptions emissive_options;
+  // 0 for no reflections, 1 for the sphere map and 2 for the cube map
+  int reflection_mode;
+  sampler2D sphere_map;
+  MapOptions sphere_options;
+  samplerCube cube_map;
 };
 uniform Material material;
 
+// Cutout materials keep fragments with at least this much alpha
+const float ALPHA_CUTOFF = 0.5;
+
+const float PI = 3.14159265;
+// The light reflected from surroundings that aren't modelled, as a fraction of
+// the base color
+const float PBR_AMBIENT = 0.03;
+
+// How far a height of 1 raises the surface, in texture coordinate units
+const float HEIGHT_SCALE = 0.05;
+
+// The normal used for lighting, set at the start of main
+vec3 surface_normal;
+
+vec3 sample_map_at(sampler2D map, MapOptions options, vec2 tex) {
+  vec4 texel = texture(map, tex * options.transform.xy + options.transform.zw);
+  vec3 color = texel.rgb;
+  if (options.channel == 3) {
+    color = vec3(texel.a);
+  } else if (options.channel == 4) {
+    color = vec3(dot(texel.rgb, vec3(0.2126, 0.7152, 0.0722)));
+  } else if (options.channel >= 0) {
+    color = vec3(texel[options.channel]);
+  }
+  return options.base_gain.x + options.base_gain.y * color;
+}
+
+vec3 sample_map(sampler2D map, MapOptions options) {
+  return sample_map_at(map, options, vert.tex);
+}
+
+// Perturbs the interpolated normal with the bump map, in the tangent space
+// given by the vertex tangent and bitangent
+vec3 bump_normal() {
+  if (material.bump_mode == 0 || length(vert.normal) == 0.0) {
+    return vert.normal;
+  }
+
+  vec3 normal;
+  if (material.bump_mode == 1) {
+    normal = sample_map(material.bump_map, material.bump_options) * 2.0 - 1.0;
+    normal.xy *= material.bump_multiplier;
+  } else {
+    // Finite differences one texel apart give the slope of the height field
+    MapOptions options = material.bump_options;
+    vec2 texel = 1.0 / (vec2(textureSize(material.bump_map, 0)) *
+                        options.transform.xy);
+    float height = sample_map_at(material.bump_map, options, vert.tex).r;
+    float height_u = sample_map_at(material.bump_map, options,
+                                   vert.tex + vec2(texel.x, 0.0)).r;
+    float height_v = sample_map_at(material.bump_map, options,
+                                   vert.tex + vec2(0.0, texel.y)).r;
+    vec2 slope = vec2(height_u - height, height_v - height) / texel *
+                 HEIGHT_SCALE * material.bump_multiplier;
+    normal = vec3(-slope, 1.0);
+  }
+
+  mat3 tbn = mat3(normalize(vert.tangent), normalize(vert.bitangent),
+                  normalize(vert.normal));
+  return normalize(tbn * normal);
+}
+
 struct DirectionalLight {
   vec3 direction;
   vec3 ambient;
@@ -29,21 +145,24 @@ struct DirectionalLight {
 vec3 calc_directional_light(DirectionalLight light) {
   // Calculate ambient component
   vec3 ambient = material.ambient * light.ambient *
-                 vec3(texture(material.ambient_map, vert_tex));
+                 sample_map(material.ambient_map, material.ambient_options);
 
   // Calculate diffuse component
   vec3 light_dir = normalize(-light.direction);
-  vec3 diffuse = material.diffuse * light.diffuse *
-                 vec3(texture(material.diffuse_map, vert_tex)) *
-                 max(dot(vert_normal, light_dir), 0.0);
+  vec3 diffuse = material.diffuse * vert.color * light.diffuse *
+                 sample_map(material.diffuse_map, material.diffuse_options) *
+                 max(dot(surface_normal, light_dir), 0.0);
 
   // Calculate specular component
-  vec3 camera_dir = normalize(camera_pos - vert_pos);
+  vec3 camera_dir = normalize(camera_pos - vert.pos);
   vec3 half_angle = normalize(light_dir + camera_dir);
   vec3 specular =
       material.specular * light.specular *
-      vec3(texture(material.specular_map, vert_tex)) *
-      pow(max(dot(vert_normal, half_angle), 0.0), material.shininess);
+      sample_map(material.specular_map, material.specular_options) *
+      pow(max(dot(surface_normal, half_angle), 0.0), material.shininess);
+  if (material.illum == 1) {
+    specular = vec3(0.0);
+  }
 
   return ambient + diffuse + specular;
 }
@@ -61,31 +180,196 @@ struct PointLight {
 vec3 calc_point_light(PointLight light) {
   // Calculate ambient component
   vec3 ambient = material.ambient * light.ambient *
-                 vec3(texture(material.ambient_map, vert_tex));
+                 sample_map(material.ambient_map, material.ambient_options);
 
   // Calculate diffuse component
-  vec3 light_dir = normalize(light.position - vert_pos);
-  vec3 diffuse = material.diffuse * light.diffuse *
-                 vec3(texture(material.diffuse_map, vert_tex)) *
-                 max(dot(vert_normal, light_dir), 0.0);
+  vec3 light_dir = normalize(light.position - vert.pos);
+  vec3 diffuse = material.diffuse * vert.color * light.diffuse *
+                 sample_map(material.diffuse_map, material.diffuse_options) *
+                 max(dot(surface_normal, light_dir), 0.0);
 
   // Calculate specular component
-  vec3 camera_dir = normalize(camera_pos - vert_pos);
+  vec3 camera_dir = normalize(camera_pos - vert.pos);
   vec3 half_angle = normalize(light_dir + camera_dir);
   vec3 specular =
       material.specular * light.specular *
-      vec3(texture(material.specular_map, vert_tex)) *
-      pow(max(dot(vert_normal, half_angle), 0.0), material.shininess);
+      sample_map(material.specular_map, material.specular_options) *
+      pow(max(dot(surface_normal, half_angle), 0.0), material.shininess);
+  if (material.illum == 1) {
+    specular = vec3(0.0);
+  }
 
   // Calculate attenuation
-  float distance = length(light.position - vert_pos);
+  float distance = length(light.position - vert.pos);
   float attenuation = 1.0 / (light.constant + light.linear * distance +
                              light.quadratic * distance * distance);
 
   return attenuation * (ambient + diffuse + specular);
 }
 
+// The surroundings reflected toward the camera, or black without a reflection
+// map
+vec3 sample_reflection() {
+  if (material.reflection_mode == 0) {
+    return vec3(0.0);
+  }
+
+  vec3 reflected =
+      reflect(normalize(vert.pos - camera_pos), normalize(surface_normal));
+  if (material.reflection_mode == 2) {
+    return texture(material.cube_map, reflected).rgb;
+  }
+  // A mirrored ball seen from +z shows each direction at the point whose normal
+  // is halfway between it and +z
+  float m = 2.0 * length(reflected + vec3(0.0, 0.0, 1.0));
+  return sample_map_at(material.sphere_map, material.sphere_options,
+                       reflected.xy / m + 0.5);
+}
+
+// The material parameters of the PBR path at this fragment
+struct Surface {
+  vec3 base_color;
+  float roughness;
+  float metallic;
+};
+
+// GGX normal distribution. Anisotropy stretches the highlight along the
+// tangent
+float distribution_ggx(vec3 half_angle, float roughness) {
+  float alpha = max(roughness * roughness, 0.002);
+  float n_dot_h = max(dot(surface_normal, half_angle), 0.0);
+  if (material.anisotropy == 0.0 || length(vert.tangent) == 0.0) {
+    float alpha2 = alpha * alpha;
+    float denominator = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * denominator * denominator);
+  }
+
+  vec3 tangent = normalize(vert.tangent - surface_normal *
+                                              dot(surface_normal, vert.tangent));
+  vec3 bitangent = cross(surface_normal, tangent);
+  float aspect = sqrt(1.0 - 0.9 * material.anisotropy);
+  float alpha_t = alpha / aspect;
+  float alpha_b = alpha * aspect;
+  float t_dot_h = dot(tangent, half_angle) / alpha_t;
+  float b_dot_h = dot(bitangent, half_angle) / alpha_b;
+  float denominator = t_dot_h * t_dot_h + b_dot_h * b_dot_h + n_dot_h * n_dot_h;
+  return 1.0 / (PI * alpha_t * alpha_b * denominator * denominator);
+}
+
+// Smith shadowing and masking with the Schlick-GGX approximation
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+  float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+  return n_dot_v / (n_dot_v * (1.0 - k) + k) *
+         (n_dot_l / (n_dot_l * (1.0 - k) + k));
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+  return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Cook-Torrance reflection of one light, with a Lambert diffuse lobe, sheen
+// and a clear coat
+vec3 calc_pbr_light(vec3 light_dir, vec3 radiance, Surface surface) {
+  vec3 camera_dir = normalize(camera_pos - vert.pos);
+  vec3 half_angle = normalize(light_dir + camera_dir);
+  float n_dot_l = max(dot(surface_normal, light_dir), 0.0);
+  float n_dot_v = max(dot(surface_normal, camera_dir), 0.0001);
+  float h_dot_v = max(dot(half_angle, camera_dir), 0.0);
+
+  vec3 f0 = mix(vec3(0.04), surface.base_color, surface.metallic);
+  vec3 fresnel = fresnel_schlick(h_dot_v, f0);
+  vec3 specular = distribution_ggx(half_angle, surface.roughness) *
+                  geometry_smith(n_dot_v, n_dot_l, surface.roughness) *
+                  fresnel / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+
+  // Metals have no diffuse reflection
+  vec3 diffuse =
+      (1.0 - fresnel) * (1.0 - surface.metallic) * surface.base_color / PI;
+  diffuse += material.sheen * pow(1.0 - h_dot_v, 5.0) / PI;
+
+  vec3 color = diffuse + specular;
+  if (material.clearcoat > 0.0) {
+    float coat_fresnel = fresnel_schlick(h_dot_v, vec3(0.04)).x;
+    float coat =
+        distribution_ggx(half_angle, material.clearcoat_roughness) *
+        geometry_smith(n_dot_v, n_dot_l, material.clearcoat_roughness) *
+        coat_fresnel / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+    color = color * (1.0 - material.clearcoat * coat_fresnel) +
+            material.clearcoat * coat;
+  }
+
+  // The lights are scaled by pi so that a rough white surface is about as
+  // bright as it is under Blinn-Phong
+  return color * radiance * PI * n_dot_l;
+}
+
+vec3 calc_pbr(DirectionalLight directional_lights[2], PointLight point_light) {
+  Surface surface;
+  surface.base_color =
+      material.diffuse * vert.color *
+      sample_map(material.diffuse_map, material.diffuse_options);
+  surface.roughness =
+      clamp(material.roughness *
+                sample_map(material.roughness_map, material.roughness_options).r,
+            0.0, 1.0);
+  surface.metallic =
+      clamp(material.metallic *
+                sample_map(material.metallic_map, material.metallic_options).r,
+            0.0, 1.0);
+
+  vec3 color = PBR_AMBIENT * surface.base_color;
+  for (int i = 0; i < 2; i++) {
+    color += calc_pbr_light(normalize(-directional_lights[i].direction),
+                            directional_lights[i].diffuse, surface);
+  }
+
+  float distance = length(point_light.position - vert.pos);
+  float attenuation =
+      1.0 / (point_light.constant + point_light.linear * distance +
+             point_light.quadratic * distance * distance);
+  color += calc_pbr_light(normalize(point_light.position - vert.pos),
+                          point_light.diffuse * attenuation, surface);
+  // Smooth surfaces mirror their surroundings by the Fresnel reflectance
+  vec3 camera_dir = normalize(camera_pos - vert.pos);
+  float n_dot_v = max(dot(surface_normal, camera_dir), 0.0);
+  vec3 f0 = mix(vec3(0.04), surface.base_color, surface.metallic);
+  color += sample_reflection() * fresnel_schlick(n_dot_v, f0) *
+           (1.0 - surface.roughness);
+  return color;
+}
+
 void main() {
+  float alpha = 1.0;
+  if (material.alpha_mode != 0) {
+    alpha = material.opacity *
+            sample_map(material.alpha_map, material.alpha_options).r;
+    if (material.alpha_mode == 1) {
+      if (alpha < ALPHA_CUTOFF) {
+        discard;
+      }
+      alpha = 1.0;
+    }
+  }
+
+  vec3 emissive = material.emissive *
+                  sample_map(material.emissive_map, material.emissive_options);
+
+  // Lines and points are drawn in their flat diffuse color
+  if (unlit) {
+    final_color = vec4(material.diffuse * vert.color, alpha);
+    return;
+  }
+
+  // Illumination model 0 is the diffuse color with no lighting at all
+  if (material.illum == 0) {
+    vec3 color = material.diffuse * vert.color *
+                 sample_map(material.diffuse_map, material.diffuse_options);
+    final_color = vec4(color + emissive, alpha);
+    return;
+  }
+
+  surface_normal = bump_normal();
+
   DirectionalLight light1 =
       DirectionalLight(vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0),
                        vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 1.0));
@@ -98,11 +382,27 @@ void main() {
       PointLight(vec3(0.0, 1.5, 0.0), vec3(1.0), vec3(0.0, 0.0, 1.0), vec3(1.0),
                  1.0, 0.22, 0.2);
 
+  if (material.pbr) {
+    DirectionalLight directional_lights[2] = DirectionalLight[2](light1, light2);
+    final_color = vec4(calc_pbr(directional_lights, light3) + emissive,
+                       alpha);
+    return;
+  }
+
   vec3 directional_lights =
       calc_directional_light(light1) + calc_directional_light(light2);
 
   vec3 point_lights = calc_point_light(light3);
 
+  // Reflections are weighted like the specular highlights
+  vec3 reflection = vec3(0.0);
+  if (material.illum != 1) {
+    reflection =
+        sample_reflection() * material.specular *
+        sample_map(material.specular_map, material.specular_options);
+  }
+
   // Sum the components together
-  final_color = vec4(directional_lights + point_lights, 1.0);
+  final_color = vec4(
+      directional_lights + point_lights + reflection + emissive, alpha);
 }
@@ -0,0 +1,65 @@
+#version 330 core
+
+// Core contexts only draw lines one pixel wide, so each segment becomes a quad instead
+layout (lines) in;
+layout (triangle_strip, max_vertices = 4) out;
+
+in Vertex {
+    vec3 pos;
+    vec2 tex;
+    vec3 normal;
+    vec3 color;
+    vec3 tangent;
+    vec3 bitangent;
+} vert_in[];
+
+out Vertex {
+    vec3 pos;
+    vec2 tex;
+    vec3 normal;
+    vec3 color;
+    vec3 tangent;
+    vec3 bitangent;
+} vert;
+
+// In pixels
+uniform vec2 viewport;
+uniform float line_width;
+
+// Emits one end of the segment, pushed sideways by an offset in normalized device coordinates
+void emit(int end, vec2 offset) {
+    vert.pos = vert_in[end].pos;
+    vert.tex = vert_in[end].tex;
+    vert.normal = vert_in[end].normal;
+    vert.color = vert_in[end].color;
+    vert.tangent = vert_in[end].tangent;
+    vert.bitangent = vert_in[end].bitangent;
+    vec4 position = gl_in[end].gl_Position;
+    // Scaled by w so the offset survives the perspective divide
+    gl_Position = position + vec4(offset * position.w, 0.0, 0.0);
+    EmitVertex();
+}
+
+void main() {
+    vec4 start = gl_in[0].gl_Position;
+    vec4 end = gl_in[1].gl_Position;
+    // Segments that reach behind the camera have no sensible direction on screen
+    if (start.w <= 0.0 || end.w <= 0.0) {
+        return;
+    }
+
+    vec2 direction = (end.xy / end.w - start.xy / start.w) * viewport;
+    if (length(direction) == 0.0) {
+        return;
+    }
+    direction = normalize(direction);
+    // Half the width on each side. A pixel is 2 / viewport in normalized device coordinates
+    vec2 offset = vec2(-direction.y, direction.x) * line_width / viewport;
+
+    // Counter-clockwise whichever way the segment points, so face culling keeps it
+    emit(0, offset);
+    emit(0, -offset);
+    emit(1, offset);
+    emit(1, -offset);
+    EndPrimitive();
+}
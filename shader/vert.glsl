@@ -3,18 +3,38 @@
 layout (location = 0) in vec3 pos;
 layout (location = 1) in vec2 tex;
 layout (location = 2) in vec3 normal;
+layout (location = 3) in vec3 color;
+layout (location = 4) in vec3 tangent;
+layout (location = 5) in vec3 bitangent;
 
-out vec3 vert_pos;
-out vec2 vert_tex;
-out vec3 vert_normal;
+out Vertex {
+    vec3 pos;
+    vec2 tex;
+    vec3 normal;
+    vec3 color;
+    vec3 tangent;
+    vec3 bitangent;
+} vert;
 
 uniform mat4 transform;
+// Whether lighting is done on linear values, so vertex colors, which tools write sRGB-encoded,
+// have to be decoded first
+uniform bool srgb;
+
+// The same curve as `srgb_to_linear` in main.rs
+vec3 srgb_to_linear(vec3 color) {
+    return mix(color / 12.92, pow((color + 0.055) / 1.055, vec3(2.4)),
+               greaterThan(color, vec3(0.04045)));
+}
 
 void main() {
     // Pass vertex attributes to fragment shader
-    vert_pos = pos;
-    vert_tex = tex;
-    vert_normal = normal;
+    vert.pos = pos;
+    vert.tex = tex;
+    vert.normal = normal;
+    vert.color = srgb ? srgb_to_linear(color) : color;
+    vert.tangent = tangent;
+    vert.bitangent = bitangent;
 
     // Apply perspective * view matrices
     gl_Position = transform * vec4(pos, 1.0);
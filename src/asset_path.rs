@@ -0,0 +1,36 @@
+use std::path::{Path, PathBuf};
+
+/// Finds a file referenced from inside an OBJ or MTL file. Absolute paths are used as is.
+/// Relative paths are tried against the referencing file's directory first, then against each
+/// search path in order. If nothing exists, the path next to the referencing file is returned so
+/// that the eventual error names a sensible location
+pub fn resolve(name: &str, referenced_from: &Path, search_paths: &[PathBuf]) -> PathBuf {
+    // Files exported on Windows often use backslashes as separators
+    let name = if cfg!(windows) {
+        name.to_string()
+    } else {
+        name.replace('\\', "/")
+    };
+    let name = Path::new(&name);
+    if name.is_absolute() {
+        return name.to_path_buf();
+    }
+
+    let beside = referenced_from.parent().unwrap_or(Path::new("")).join(name);
+    if beside.is_file() {
+        return beside;
+    }
+    search_paths
+        .iter()
+        .map(|search_path| search_path.join(name))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or(beside)
+}
+
+/// Whether two paths name the same file, even if they're spelled differently
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
@@ -0,0 +1,227 @@
+use gl33::global_loader::*;
+use gl33::*;
+
+use crate::material_gen::get_uniform;
+use crate::shader::compile_program;
+
+/// Brightness above which the scene starts to glow
+const THRESHOLD: f32 = 1.0;
+/// How strongly the blurred glow is added back onto the scene
+const INTENSITY: f32 = 0.8;
+/// Horizontal and vertical blur pairs. Each one widens the glow
+const BLUR_PASSES: usize = 4;
+
+/// Renders the scene into a floating-point target so that colors brighter than white survive,
+/// then blurs those bright parts and adds them back so they glow
+pub struct Bloom {
+    width: i32,
+    height: i32,
+    samples: i32,
+    /// Multisampled HDR color and depth that the scene is drawn into
+    scene_framebuffer: u32,
+    scene_renderbuffers: [u32; 2],
+    /// The scene resolved to one sample, so it can be read as a texture
+    resolved_framebuffer: u32,
+    resolved_texture: u32,
+    /// Half resolution targets that the blur bounces between
+    blur_framebuffers: [u32; 2],
+    blur_textures: [u32; 2],
+    /// Empty, since the full-screen triangle is made from `gl_VertexID`
+    vao: u32,
+    bright_program: u32,
+    blur_program: u32,
+    composite_program: u32,
+    horizontal_uniform: i32,
+}
+
+/// Creates a linearly filtered texture that stays inside its edges, for sampling a render target
+unsafe fn target_texture() -> u32 {
+    let mut texture = 0;
+    glGenTextures(1, &mut texture);
+    glBindTexture(GL_TEXTURE_2D, texture);
+    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, GL_LINEAR.0 as _);
+    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_LINEAR.0 as _);
+    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, GL_CLAMP_TO_EDGE.0 as _);
+    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, GL_CLAMP_TO_EDGE.0 as _);
+    texture
+}
+
+impl Bloom {
+    /// Compiles the post-processing shaders. The render targets are sized on the first `begin`
+    pub unsafe fn new() -> Self {
+        let mut max_samples = 0;
+        glGetIntegerv(GL_MAX_SAMPLES, &mut max_samples);
+
+        let mut scene_framebuffer = 0;
+        let mut scene_renderbuffers = [0; 2];
+        let mut resolved_framebuffer = 0;
+        let mut blur_framebuffers = [0; 2];
+        let mut vao = 0;
+        glGenFramebuffers(1, &mut scene_framebuffer);
+        glGenRenderbuffers(2, scene_renderbuffers.as_mut_ptr());
+        glGenFramebuffers(1, &mut resolved_framebuffer);
+        glGenFramebuffers(2, blur_framebuffers.as_mut_ptr());
+        glGenVertexArrays(1, &mut vao);
+
+        let bright_program = compile_program("shader/post_vert.glsl", "shader/bright_frag.glsl");
+        let blur_program = compile_program("shader/post_vert.glsl", "shader/blur_frag.glsl");
+        let composite_program =
+            compile_program("shader/post_vert.glsl", "shader/composite_frag.glsl");
+        glUseProgram(bright_program);
+        glUniform1i(get_uniform(bright_program, c"scene"), 0);
+        glUniform1f(get_uniform(bright_program, c"threshold"), THRESHOLD);
+        glUseProgram(blur_program);
+        glUniform1i(get_uniform(blur_program, c"image"), 0);
+        let horizontal_uniform = get_uniform(blur_program, c"horizontal");
+        glUseProgram(composite_program);
+        glUniform1i(get_uniform(composite_program, c"scene"), 0);
+        glUniform1i(get_uniform(composite_program, c"bloom"), 1);
+        glUniform1f(get_uniform(composite_program, c"intensity"), INTENSITY);
+
+        Bloom {
+            width: 0,
+            height: 0,
+            samples: max_samples.min(16),
+            scene_framebuffer,
+            scene_renderbuffers,
+            resolved_framebuffer,
+            resolved_texture: target_texture(),
+            blur_framebuffers,
+            blur_textures: [target_texture(), target_texture()],
+            vao,
+            bright_program,
+            blur_program,
+            composite_program,
+            horizontal_uniform,
+        }
+    }
+
+    /// Reallocates every target for a new window size
+    unsafe fn resize(&mut self, width: i32, height: i32) {
+        self.width = width;
+        self.height = height;
+
+        glBindFramebuffer(GL_FRAMEBUFFER, self.scene_framebuffer);
+        let attachments = [
+            (GL_RGBA16F, GL_COLOR_ATTACHMENT0),
+            (GL_DEPTH_COMPONENT24, GL_DEPTH_ATTACHMENT),
+        ];
+        for (&renderbuffer, (format, attachment)) in
+            self.scene_renderbuffers.iter().zip(attachments)
+        {
+            glBindRenderbuffer(GL_RENDERBUFFER, renderbuffer);
+            glRenderbufferStorageMultisample(GL_RENDERBUFFER, self.samples, format, width, height);
+            glFramebufferRenderbuffer(GL_FRAMEBUFFER, attachment, GL_RENDERBUFFER, renderbuffer);
+        }
+
+        let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+        let targets = [
+            (
+                self.resolved_framebuffer,
+                self.resolved_texture,
+                width,
+                height,
+            ),
+            (
+                self.blur_framebuffers[0],
+                self.blur_textures[0],
+                half_width,
+                half_height,
+            ),
+            (
+                self.blur_framebuffers[1],
+                self.blur_textures[1],
+                half_width,
+                half_height,
+            ),
+        ];
+        for (framebuffer, texture, width, height) in targets {
+            glBindTexture(GL_TEXTURE_2D, texture);
+            glTexImage2D(
+                GL_TEXTURE_2D,
+                0,
+                GL_RGBA16F.0 as _,
+                width,
+                height,
+                0,
+                GL_RGBA,
+                GL_FLOAT,
+                std::ptr::null(),
+            );
+            glBindFramebuffer(GL_FRAMEBUFFER, framebuffer);
+            glFramebufferTexture2D(
+                GL_FRAMEBUFFER,
+                GL_COLOR_ATTACHMENT0,
+                GL_TEXTURE_2D,
+                texture,
+                0,
+            );
+        }
+    }
+
+    /// Directs drawing into the HDR target, resizing it first if the window changed size
+    pub unsafe fn begin(&mut self, width: i32, height: i32) {
+        if (width, height) != (self.width, self.height) {
+            self.resize(width, height);
+        }
+        glBindFramebuffer(GL_FRAMEBUFFER, self.scene_framebuffer);
+    }
+
+    /// Blurs the bright parts of what was drawn since `begin` and composites the result into
+    /// the window. The program, vertex array and viewport are restored afterwards
+    pub unsafe fn finish(&self) {
+        let mut program = 0;
+        let mut vao = 0;
+        glGetIntegerv(GL_CURRENT_PROGRAM, &mut program);
+        glGetIntegerv(GL_VERTEX_ARRAY_BINDING, &mut vao);
+
+        glBindFramebuffer(GL_READ_FRAMEBUFFER, self.scene_framebuffer);
+        glBindFramebuffer(GL_DRAW_FRAMEBUFFER, self.resolved_framebuffer);
+        glBlitFramebuffer(
+            0,
+            0,
+            self.width,
+            self.height,
+            0,
+            0,
+            self.width,
+            self.height,
+            GL_COLOR_BUFFER_BIT,
+            GL_NEAREST,
+        );
+
+        glDisable(GL_DEPTH_TEST);
+        glBindVertexArray(self.vao);
+        glActiveTexture(GL_TEXTURE0);
+
+        // Keep only what's brighter than the threshold, at half resolution
+        glViewport(0, 0, (self.width / 2).max(1), (self.height / 2).max(1));
+        glUseProgram(self.bright_program);
+        glBindFramebuffer(GL_FRAMEBUFFER, self.blur_framebuffers[0]);
+        glBindTexture(GL_TEXTURE_2D, self.resolved_texture);
+        glDrawArrays(GL_TRIANGLES, 0, 3);
+
+        // A separable Gaussian, horizontal into the second target and vertical back
+        glUseProgram(self.blur_program);
+        for _ in 0..BLUR_PASSES {
+            for (source, destination) in [(0, 1), (1, 0)] {
+                glUniform1i(self.horizontal_uniform, (destination == 1) as i32);
+                glBindFramebuffer(GL_FRAMEBUFFER, self.blur_framebuffers[destination]);
+                glBindTexture(GL_TEXTURE_2D, self.blur_textures[source]);
+                glDrawArrays(GL_TRIANGLES, 0, 3);
+            }
+        }
+
+        glViewport(0, 0, self.width, self.height);
+        glUseProgram(self.composite_program);
+        glBindFramebuffer(GL_FRAMEBUFFER, 0);
+        glBindTexture(GL_TEXTURE_2D, self.resolved_texture);
+        glActiveTexture(GL_TEXTURE1);
+        glBindTexture(GL_TEXTURE_2D, self.blur_textures[0]);
+        glDrawArrays(GL_TRIANGLES, 0, 3);
+
+        glEnable(GL_DEPTH_TEST);
+        glUseProgram(program as u32);
+        glBindVertexArray(vao as u32);
+    }
+}
@@ -0,0 +1,298 @@
+use crate::vertex_gen::{VertexColor, VertexNormal, VertexPos, VertexTex};
+
+/// The free-form types from `cstype` that can be tessellated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Basis {
+    Bezier,
+    BSpline,
+}
+
+/// A control point of a free-form curve or surface, with the attributes of the vertex it refers to
+pub struct ControlPoint {
+    pub position: VertexPos,
+    /// The `w` of the vertex, which only matters for rational types
+    pub weight: f32,
+    pub color: VertexColor,
+    /// Only surface control points use texture coordinates
+    pub uv: Option<VertexTex>,
+}
+
+/// A curve or surface from a `curv` or `surf` statement and the `parm` statements after it
+pub struct FreeForm {
+    pub basis: Basis,
+    pub rational: bool,
+    /// The degree in u and v. Curves only use u
+    pub degree: [usize; 2],
+    /// The global parameter range to tessellate in each direction, one for a curve and two for a
+    /// surface
+    pub ranges: Vec<[f32; 2]>,
+    /// Control points in file order, where u varies fastest
+    pub points: Vec<ControlPoint>,
+    /// The `parm u` and `parm v` values
+    pub parms: [Vec<f32>; 2],
+}
+
+/// A point on a tessellated surface
+pub struct SurfaceVertex {
+    pub position: VertexPos,
+    pub uv: VertexTex,
+    /// Zero where the surface is degenerate, such as at the pole of a sphere
+    pub normal: VertexNormal,
+    pub color: VertexColor,
+}
+
+pub enum Tessellation {
+    /// Points along a curve, to be joined into a line strip
+    Curve(Vec<(VertexPos, VertexColor)>),
+    /// A grid of vertices and the counterclockwise triangles that cover it
+    Surface(Vec<SurfaceVertex>, Vec<[usize; 3]>),
+}
+
+/// The knot vector of one parametric direction, and where it gets sampled
+struct Axis {
+    degree: usize,
+    knots: Vec<f32>,
+    /// The number of control points in this direction
+    count: usize,
+    samples: Vec<f32>,
+}
+
+impl Axis {
+    /// Builds the B-spline knot vector for one direction. Bezier `parm` values are the boundaries
+    /// between segments, which become knots repeated `degree` times. Returns `None` if the values
+    /// can't describe a curve of this degree
+    fn new(
+        basis: Basis,
+        degree: usize,
+        parms: &[f32],
+        range: [f32; 2],
+        density: u32,
+    ) -> Option<Self> {
+        if degree == 0 || parms.len() < 2 || parms.windows(2).any(|pair| pair[0] > pair[1]) {
+            return None;
+        }
+        let knots = match basis {
+            Basis::Bezier => {
+                let mut knots = vec![parms[0]; degree + 1];
+                for &parm in &parms[1..parms.len() - 1] {
+                    knots.extend(std::iter::repeat_n(parm, degree));
+                }
+                knots.extend(std::iter::repeat_n(parms[parms.len() - 1], degree + 1));
+                knots
+            }
+            Basis::BSpline => parms.to_vec(),
+        };
+        let count = knots.len().checked_sub(degree + 1)?;
+        if count <= degree {
+            return None;
+        }
+
+        // Only the part of the range where the basis functions sum to one can be evaluated
+        let domain = [knots[degree], knots[count]];
+        let start = range[0].min(range[1]).max(domain[0]);
+        let end = range[0].max(range[1]).min(domain[1]);
+        if start >= end {
+            return None;
+        }
+        // Split every knot span in the range evenly, so that the tessellation follows the curve's
+        // own pieces
+        let mut breaks = vec![start];
+        breaks.extend(knots.iter().filter(|&&knot| knot > start && knot < end));
+        breaks.push(end);
+        breaks.dedup();
+        let density = density.max(1);
+        let mut samples: Vec<f32> = breaks
+            .windows(2)
+            .flat_map(|pair| {
+                (0..density)
+                    .map(move |step| pair[0] + (pair[1] - pair[0]) * step as f32 / density as f32)
+            })
+            .collect();
+        samples.push(end);
+
+        Some(Axis {
+            degree,
+            knots,
+            count,
+            samples,
+        })
+    }
+
+    /// Returns the index of the first control point that affects `t`, and the values and first
+    /// derivatives of the `degree + 1` basis functions that are nonzero there
+    fn basis(&self, t: f32) -> (usize, Vec<f32>, Vec<f32>) {
+        let (p, knots) = (self.degree, &self.knots);
+        let mut span = knots
+            .partition_point(|&knot| knot <= t)
+            .saturating_sub(1)
+            .clamp(p, self.count - 1);
+        while span > p && knots[span] == knots[span + 1] {
+            span -= 1;
+        }
+
+        // Cox-de Boor, building up from degree 0. The degree p - 1 values give the derivatives
+        let mut values = vec![0.0; p + 1];
+        let mut lower = vec![];
+        let mut left = vec![0.0; p + 1];
+        let mut right = vec![0.0; p + 1];
+        values[0] = 1.0;
+        for j in 1..=p {
+            left[j] = t - knots[span + 1 - j];
+            right[j] = knots[span + j] - t;
+            if j == p {
+                lower = values[..p].to_vec();
+            }
+            let mut saved = 0.0;
+            for r in 0..j {
+                let temp = values[r] / (right[r + 1] + left[j - r]);
+                values[r] = saved + right[r + 1] * temp;
+                saved = left[j - r] * temp;
+            }
+            values[j] = saved;
+        }
+
+        let first = span - p;
+        let derivatives = (0..=p)
+            .map(|k| {
+                let i = first + k;
+                let rising = if k > 0 {
+                    lower[k - 1] / (knots[i + p] - knots[i])
+                } else {
+                    0.0
+                };
+                let falling = if k < p {
+                    lower[k] / (knots[i + p + 1] - knots[i + 1])
+                } else {
+                    0.0
+                };
+                p as f32 * (rising - falling)
+            })
+            .collect();
+        (first, values, derivatives)
+    }
+}
+
+impl FreeForm {
+    fn weight(&self, point: &ControlPoint) -> f32 {
+        if self.rational {
+            point.weight
+        } else {
+            1.0
+        }
+    }
+
+    /// Samples the curve or surface `density` times across each knot span. Returns `None` if the
+    /// degree, knots and number of control points don't fit together
+    pub fn tessellate(&self, density: u32) -> Option<Tessellation> {
+        let axes = self
+            .ranges
+            .iter()
+            .enumerate()
+            .map(|(i, &range)| {
+                Axis::new(self.basis, self.degree[i], &self.parms[i], range, density)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if axes.iter().map(|axis| axis.count).product::<usize>() != self.points.len() {
+            return None;
+        }
+
+        match axes.as_slice() {
+            [axis] => Some(Tessellation::Curve(
+                axis.samples
+                    .iter()
+                    .map(|&t| {
+                        let (first, values, _) = axis.basis(t);
+                        let mut position = glm::vec4(0.0, 0.0, 0.0, 0.0);
+                        let mut color = glm::vec3(0.0, 0.0, 0.0);
+                        for (point, value) in self.points[first..].iter().zip(values) {
+                            let weight = self.weight(point) * value;
+                            position += glm::Vec3::from(point.position).push(1.0) * weight;
+                            color += glm::Vec3::from(point.color) * weight;
+                        }
+                        let color = color / position.w;
+                        let position = position.xyz() / position.w;
+                        (
+                            [position.x, position.y, position.z],
+                            [color.x, color.y, color.z],
+                        )
+                    })
+                    .collect(),
+            )),
+            [u_axis, v_axis] => Some(self.tessellate_surface(u_axis, v_axis)),
+            _ => None,
+        }
+    }
+
+    fn tessellate_surface(&self, u_axis: &Axis, v_axis: &Axis) -> Tessellation {
+        // Without texture coordinates on every control point, the surface parameters stand in
+        let has_uvs = self.points.iter().all(|point| point.uv.is_some());
+        let u_bases: Vec<_> = u_axis.samples.iter().map(|&u| u_axis.basis(u)).collect();
+        let v_bases: Vec<_> = v_axis.samples.iter().map(|&v| v_axis.basis(v)).collect();
+        let [u_start, u_end] = [u_axis.samples[0], u_axis.samples[u_axis.samples.len() - 1]];
+        let [v_start, v_end] = [v_axis.samples[0], v_axis.samples[v_axis.samples.len() - 1]];
+
+        let mut vertices = Vec::with_capacity(u_bases.len() * v_bases.len());
+        for (&v, (v_first, v_values, v_derivatives)) in v_axis.samples.iter().zip(&v_bases) {
+            for (&u, (u_first, u_values, u_derivatives)) in u_axis.samples.iter().zip(&u_bases) {
+                // Homogeneous sums, so rational surfaces only divide by the weight once
+                let mut point = glm::vec4(0.0, 0.0, 0.0, 0.0);
+                let mut du = glm::vec4(0.0, 0.0, 0.0, 0.0);
+                let mut dv = glm::vec4(0.0, 0.0, 0.0, 0.0);
+                let mut uv = glm::vec2(0.0, 0.0);
+                let mut color = glm::vec3(0.0, 0.0, 0.0);
+                for (b, (&v_value, &v_derivative)) in v_values.iter().zip(v_derivatives).enumerate()
+                {
+                    for (a, (&u_value, &u_derivative)) in
+                        u_values.iter().zip(u_derivatives).enumerate()
+                    {
+                        let control = &self.points[(v_first + b) * u_axis.count + u_first + a];
+                        let weight = self.weight(control);
+                        let homogeneous = glm::Vec3::from(control.position).push(1.0) * weight;
+                        point += homogeneous * u_value * v_value;
+                        du += homogeneous * u_derivative * v_value;
+                        dv += homogeneous * u_value * v_derivative;
+                        uv += glm::Vec2::from(control.uv.unwrap_or_default())
+                            * (weight * u_value * v_value);
+                        color += glm::Vec3::from(control.color) * (weight * u_value * v_value);
+                    }
+                }
+                let position = point.xyz() / point.w;
+                let du = (du.xyz() - position * du.w) / point.w;
+                let dv = (dv.xyz() - position * dv.w) / point.w;
+                let normal = glm::cross(&du, &dv);
+                let normal = if glm::length(&normal) > f32::EPSILON {
+                    glm::normalize(&normal)
+                } else {
+                    glm::vec3(0.0, 0.0, 0.0)
+                };
+                let uv = if has_uvs {
+                    uv / point.w
+                } else {
+                    glm::vec2(
+                        (u - u_start) / (u_end - u_start),
+                        (v - v_start) / (v_end - v_start),
+                    )
+                };
+                let color = color / point.w;
+                vertices.push(SurfaceVertex {
+                    position: [position.x, position.y, position.z],
+                    uv: [uv.x, uv.y],
+                    normal: [normal.x, normal.y, normal.z],
+                    color: [color.x, color.y, color.z],
+                });
+            }
+        }
+
+        let columns = u_bases.len();
+        let mut triangles = Vec::with_capacity((columns - 1) * (v_bases.len() - 1) * 2);
+        for row in 0..v_bases.len() - 1 {
+            for column in 0..columns - 1 {
+                let corner = row * columns + column;
+                let above = corner + columns;
+                triangles.push([corner, corner + 1, above + 1]);
+                triangles.push([corner, above + 1, above]);
+            }
+        }
+        Tessellation::Surface(vertices, triangles)
+    }
+}
@@ -1,12 +1,42 @@
 use fermium::prelude::*;
 use gl33::global_loader::*;
 use gl33::*;
-use std::{f32::consts::PI, fs::File, io::Read};
+use std::{
+    f32::consts::PI,
+    path::{Path, PathBuf},
+};
 
 mod vertex_gen;
 use vertex_gen::*;
 
+mod asset_path;
+
+mod bloom;
+use bloom::Bloom;
+
+mod freeform;
+
+mod map_options;
+
 mod material_gen;
+use material_gen::{get_uniform, AlphaMode, MaterialUniforms};
+
+mod mesh_cache;
+
+mod normal_gen;
+
+mod obj_error;
+
+mod obj_parse;
+
+mod shader;
+use shader::{compile_program, compile_program_with_geometry};
+
+mod tangent_gen;
+
+mod texture_cache;
+
+mod triangulate;
 
 extern crate nalgebra_glm as glm;
 
@@ -14,7 +44,74 @@ unsafe fn is_key_down(keystate: *const u8, code: SDL_Scancode) -> bool {
     *keystate.offset(code.0 as isize) != 0
 }
 
+/// The background, as it appears on screen
+const CLEAR_COLOR: [f32; 3] = [0.2, 0.3, 0.3];
+
+/// Converts an sRGB-encoded channel to a linear value
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Switches between encoding linear colors as sRGB on their way to the window, and writing
+/// colors as they are. The programs decode vertex colors to match
+unsafe fn set_srgb_output(enabled: bool, programs: [&SceneUniforms; 2]) {
+    for uniforms in programs {
+        glUseProgram(uniforms.program);
+        glUniform1i(uniforms.srgb, enabled as i32);
+    }
+    // Clears are encoded too, so the clear color is given linearly to look the same either way
+    let [r, g, b] = if enabled {
+        CLEAR_COLOR.map(srgb_to_linear)
+    } else {
+        CLEAR_COLOR
+    };
+    glClearColor(r, g, b, 1.0);
+    if enabled {
+        glEnable(GL_FRAMEBUFFER_SRGB);
+    } else {
+        glDisable(GL_FRAMEBUFFER_SRGB);
+    }
+}
+
+/// The uniforms of a program that draws the model
+struct SceneUniforms {
+    program: u32,
+    material: MaterialUniforms,
+    unlit: i32,
+    transform: i32,
+    camera_pos: i32,
+    srgb: i32,
+}
+
+impl SceneUniforms {
+    /// Looks up the uniforms, leaving the program in use
+    unsafe fn new(program: u32) -> Self {
+        glUseProgram(program);
+        SceneUniforms {
+            program,
+            material: MaterialUniforms::new(program),
+            unlit: get_uniform(program, c"unlit"),
+            transform: get_uniform(program, c"transform"),
+            camera_pos: get_uniform(program, c"camera_pos"),
+            srgb: get_uniform(program, c"srgb"),
+        }
+    }
+}
+
 fn main() {
+    // `--bench <file.obj>` measures the parser instead of opening the viewer
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag, filepath] = args.as_slice() {
+        if flag == "--bench" {
+            obj_parse::bench(Path::new(filepath));
+            return;
+        }
+    }
+
     unsafe {
         // Initialize SDL/Fermium
         assert_eq!(
@@ -23,9 +120,12 @@ fn main() {
             "SDL/Fermium could not be inited"
         );
 
+        // The window's pixel format is picked when it's created, so this has to come first
+        assert_eq!(SDL_GL_SetAttribute(SDL_GL_FRAMEBUFFER_SRGB_CAPABLE, 1), 0);
+
         // Create a window
         let win = SDL_CreateWindow(
-            "Window Title\0".as_ptr().cast(),
+            c"Window Title".as_ptr().cast(),
             SDL_WINDOWPOS_CENTERED,
             SDL_WINDOWPOS_CENTERED,
             800,
@@ -66,6 +166,7 @@ fn main() {
         glEnable(GL_MULTISAMPLE);
         glEnable(GL_CULL_FACE);
         glEnable(GL_DEPTH_TEST);
+        glEnable(GL_TEXTURE_CUBE_MAP_SEAMLESS);
         glDepthFunc(GL_LEQUAL);
 
         // Buffer object initialization
@@ -77,195 +178,155 @@ fn main() {
         let mut vbo = 0;
         glGenBuffers(1, &mut vbo);
         glBindBuffer(GL_ARRAY_BUFFER, vbo);
-        glVertexAttribPointer(0, 3, GL_FLOAT, 0, 8 * 4, 0 as *const _);
-        glVertexAttribPointer(1, 2, GL_FLOAT, 0, 8 * 4, (3 * 4) as *const _);
-        glVertexAttribPointer(2, 3, GL_FLOAT, 0, 8 * 4, (5 * 4) as *const _);
+        glVertexAttribPointer(0, 3, GL_FLOAT, 0, 17 * 4, std::ptr::null());
+        glVertexAttribPointer(1, 2, GL_FLOAT, 0, 17 * 4, (3 * 4) as *const _);
+        glVertexAttribPointer(2, 3, GL_FLOAT, 0, 17 * 4, (5 * 4) as *const _);
+        glVertexAttribPointer(3, 3, GL_FLOAT, 0, 17 * 4, (8 * 4) as *const _);
+        glVertexAttribPointer(4, 3, GL_FLOAT, 0, 17 * 4, (11 * 4) as *const _);
+        glVertexAttribPointer(5, 3, GL_FLOAT, 0, 17 * 4, (14 * 4) as *const _);
         glEnableVertexAttribArray(0);
         glEnableVertexAttribArray(1);
         glEnableVertexAttribArray(2);
-
-        // Compile and source vertex shader at shader/vert.glsl
-        let vertex_shader = glCreateShader(GL_VERTEX_SHADER);
-        assert_ne!(vertex_shader, 0, "Vertex shader was null");
-
-        let mut vertex_file = File::open("shader/vert.glsl").expect("Couldn't open vert.glsl");
-        let mut vertex_source = String::default();
-        let vertex_length = vertex_file
-            .read_to_string(&mut vertex_source)
-            .expect("Read to string failed");
-        drop(vertex_file);
-
-        glShaderSource(
-            vertex_shader,
-            1,
-            &(vertex_source.as_bytes().as_ptr().cast()),
-            &(vertex_length as i32),
-        );
-
-        glCompileShader(vertex_shader);
-
-        let mut success = 0;
-        glGetShaderiv(vertex_shader, GL_COMPILE_STATUS, &mut success);
-        if success == 0 {
-            let mut v: Vec<u8> = Vec::with_capacity(1024);
-            let mut log_len = 0_i32;
-            glGetShaderInfoLog(vertex_shader, 1024, &mut log_len, v.as_mut_ptr().cast());
-            v.set_len(log_len.try_into().unwrap());
-            panic!("Vertex Compile Error: {}", String::from_utf8_lossy(&v));
-        }
-
-        // Compile and source frag shader at shader/vert.glsl
-        let fragment_shader = glCreateShader(GL_FRAGMENT_SHADER);
-        assert_ne!(fragment_shader, 0, "Fragment shader was null");
-
-        let mut fragment_file = File::open("shader/frag.glsl").expect("Couldn't open frag.glsl");
-        let mut fragment_source = String::default();
-        let fragment_length = fragment_file
-            .read_to_string(&mut fragment_source)
-            .expect("Read to string failed");
-        drop(fragment_file);
-
-        glShaderSource(
-            fragment_shader,
-            1,
-            &(fragment_source.as_bytes().as_ptr().cast()),
-            &(fragment_length as i32),
+        glEnableVertexAttribArray(3);
+        glEnableVertexAttribArray(4);
+        glEnableVertexAttribArray(5);
+
+        let mut ebo = 0;
+        glGenBuffers(1, &mut ebo);
+        glBindBuffer(GL_ELEMENT_ARRAY_BUFFER, ebo);
+
+        let shader_program = compile_program("shader/vert.glsl", "shader/frag.glsl");
+        // Core contexts don't draw lines wider than a pixel, so lines get a program that widens
+        // them into quads
+        let line_program = compile_program_with_geometry(
+            "shader/vert.glsl",
+            "shader/line_geom.glsl",
+            "shader/frag.glsl",
         );
 
-        glCompileShader(fragment_shader);
-
-        let mut success = 0;
-        glGetShaderiv(fragment_shader, GL_COMPILE_STATUS, &mut success);
-        if success == 0 {
-            let mut v: Vec<u8> = Vec::with_capacity(1024);
-            let mut log_len = 0_i32;
-            glGetShaderInfoLog(fragment_shader, 1024, &mut log_len, v.as_mut_ptr().cast());
-            v.set_len(log_len.try_into().unwrap());
-            panic!("Fragment Compile Error: {}", String::from_utf8_lossy(&v));
-        }
-
-        // Attach shaders to shader program
-        let shader_program = glCreateProgram();
-        glAttachShader(shader_program, vertex_shader);
-        glAttachShader(shader_program, fragment_shader);
-        glLinkProgram(shader_program);
-
-        let mut success = 0;
-        glGetProgramiv(shader_program, GL_LINK_STATUS, &mut success);
-        if success == 0 {
-            let mut v: Vec<u8> = Vec::with_capacity(1024);
-            let mut log_len = 0_i32;
-            glGetProgramInfoLog(shader_program, 1024, &mut log_len, v.as_mut_ptr().cast());
-            v.set_len(log_len.try_into().unwrap());
-            panic!("Program Link Error: {}", String::from_utf8_lossy(&v));
-        }
-
-        // Clean up shaders
-        glDeleteShader(vertex_shader);
-        glDeleteShader(fragment_shader);
-
         // Set program
         glUseProgram(shader_program);
 
-        // Set clear color
-        glClearColor(0.2, 0.3, 0.3, 1.0);
-
         // Get mesh and material data
-        let (mesh, material) = get_mesh_data("mesh/cube.obj");
-
-        // Get uniform locations
-        let ambient_map_uniform =
-            glGetUniformLocation(shader_program, "material.ambient_map\0".as_ptr());
-        assert_ne!(
-            ambient_map_uniform, -1,
-            "Uniform \"material.ambient_map\" does not exist"
-        );
-        glUniform1i(ambient_map_uniform, 0);
-
-        let diffuse_map_uniform =
-            glGetUniformLocation(shader_program, "material.diffuse_map\0".as_ptr());
-        assert_ne!(
-            diffuse_map_uniform, -1,
-            "Uniform \"material.diffuse_map\" does not exist"
-        );
-        glUniform1i(diffuse_map_uniform, 1);
-
-        let specular_map_uniform =
-            glGetUniformLocation(shader_program, "material.specular_map\0".as_ptr());
-        assert_ne!(
-            specular_map_uniform, -1,
-            "Uniform \"material.specular_map\" does not exist"
-        );
-        glUniform1i(specular_map_uniform, 2);
-
-        let transform_uniform = glGetUniformLocation(shader_program, "transform\0".as_ptr());
-        assert_ne!(
-            transform_uniform, -1,
-            "Uniform \"transform\" does not exist"
-        );
-
-        let camera_pos_uniform = glGetUniformLocation(shader_program, "camera_pos\0".as_ptr());
-        assert_ne!(
-            camera_pos_uniform, -1,
-            "Uniform \"camera_pos\" does not exist"
-        );
-
-        let ambient_uniform = glGetUniformLocation(shader_program, "material.ambient\0".as_ptr());
-        assert_ne!(
-            ambient_uniform, -1,
-            "Uniform \"material.ambient\" does not exist"
-        );
+        // The bundled assets keep materials and textures in their own directories
+        let options = LoadOptions {
+            search_paths: vec![PathBuf::from("material"), PathBuf::from("texture")],
+            cache_dir: Some(PathBuf::from("cache")),
+            ..LoadOptions::default()
+        };
+        let mut model = match get_mesh_data("mesh/cube.obj", &options) {
+            Ok(model) => model,
+            Err(err) => {
+                // Keep the viewer running with nothing loaded rather than taking down the process
+                eprintln!("Failed to load mesh: {err}");
+                Model {
+                    vertices: vec![],
+                    indices: Indices::U16(vec![]),
+                    submeshes: vec![],
+                    materials: vec![],
+                }
+            }
+        };
 
-        let diffuse_uniform = glGetUniformLocation(shader_program, "material.diffuse\0".as_ptr());
-        assert_ne!(
-            diffuse_uniform, -1,
-            "Uniform \"material.diffuse\" does not exist"
+        // Upload the mesh once, since it doesn't change between frames
+        glBindBuffer(GL_ARRAY_BUFFER, vbo);
+        glBufferData(
+            GL_ARRAY_BUFFER,
+            (model.vertices.len() * (4 * 17)) as isize,
+            model.vertices.as_ptr().cast(),
+            GL_STATIC_DRAW,
         );
-
-        let specular_uniform = glGetUniformLocation(shader_program, "material.specular\0".as_ptr());
-        assert_ne!(
-            specular_uniform, -1,
-            "Uniform \"material.specular\" does not exist"
+        let (index_data, index_type) = match &model.indices {
+            Indices::U16(indices) => (indices.as_ptr().cast(), GL_UNSIGNED_SHORT),
+            Indices::U32(indices) => (indices.as_ptr().cast(), GL_UNSIGNED_INT),
+        };
+        glBufferData(
+            GL_ELEMENT_ARRAY_BUFFER,
+            (model.indices.len() * model.indices.size_of_index()) as isize,
+            index_data,
+            GL_STATIC_DRAW,
         );
 
-        let shininess_uniform =
-            glGetUniformLocation(shader_program, "material.shininess\0".as_ptr());
-        assert_ne!(
-            shininess_uniform, -1,
-            "Uniform \"material.shininess\" does not exist"
-        );
+        // Find every range with a blended material and its center once, since only their order
+        // changes between frames. Each is the submesh, batch and range index
+        let mut blended_ranges = vec![];
+        for (submesh_index, submesh) in model.submeshes.iter().enumerate() {
+            for (batch_index, (_, ranges)) in submesh.batches().into_iter().enumerate() {
+                for (range_index, range) in ranges.iter().enumerate() {
+                    if model.materials[range.material].desc.alpha_mode() == AlphaMode::Blend {
+                        let center = model.center(range);
+                        blended_ranges.push((submesh_index, batch_index, range_index, center));
+                    }
+                }
+            }
+        }
+        glBlendFunc(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
 
-        glUniform3f(
-            ambient_uniform,
-            material.ambient[0],
-            material.ambient[1],
-            material.ambient[2],
-        );
-        glUniform3f(
-            diffuse_uniform,
-            material.diffuse[0],
-            material.diffuse[1],
-            material.diffuse[2],
-        );
-        glUniform3f(
-            specular_uniform,
-            material.specular[0],
-            material.specular[1],
-            material.specular[2],
-        );
-        glUniform1f(shininess_uniform, material.shininess);
+        // Get uniform locations
+        let line_uniforms = SceneUniforms::new(line_program);
+        let viewport_uniform = get_uniform(line_program, c"viewport");
+        let line_width_uniform = get_uniform(line_program, c"line_width");
+        let scene_uniforms = SceneUniforms::new(shader_program);
 
         // Cross-frame state variables
         let mut azimuth = PI / 4.0;
         let mut elevation = PI / 4.0;
         let mut distance = 3.0;
+        let mut line_width: f32 = 1.0;
+        let mut point_size: f32 = 4.0;
+        let mut bloom = Bloom::new();
+        let mut bloom_enabled = true;
+        let mut srgb = options.srgb;
+        set_srgb_output(srgb, [&line_uniforms, &scene_uniforms]);
+        glUseProgram(shader_program);
 
         'main_loop: loop {
             let mut event = SDL_Event::default();
             while SDL_PollEvent(&mut event) != 0 {
-                match event.type_ {
-                    SDL_QUIT => break 'main_loop,
-                    _ => {}
+                if event.type_ == SDL_QUIT {
+                    break 'main_loop;
+                }
+                // Brackets resize lines, and minus and equals resize points
+                if event.type_ == SDL_KEYDOWN {
+                    match event.key.keysym.scancode {
+                        SDL_SCANCODE_LEFTBRACKET => line_width = f32::max(line_width - 1.0, 1.0),
+                        SDL_SCANCODE_RIGHTBRACKET => line_width += 1.0,
+                        SDL_SCANCODE_MINUS => point_size = f32::max(point_size - 1.0, 1.0),
+                        SDL_SCANCODE_EQUALS => point_size += 1.0,
+                        _ => {}
+                    }
+                }
+                // Number keys toggle the visibility of the first nine submeshes
+                if event.type_ == SDL_KEYDOWN && event.key.repeat == 0 {
+                    let scancode = event.key.keysym.scancode;
+                    if (SDL_SCANCODE_1.0..=SDL_SCANCODE_9.0).contains(&scancode.0) {
+                        let index = (scancode.0 - SDL_SCANCODE_1.0) as usize;
+                        if let Some(submesh) = model.submeshes.get_mut(index) {
+                            submesh.visible = !submesh.visible;
+                            let state = if submesh.visible { "shown" } else { "hidden" };
+                            println!(
+                                "Submesh \"{}\" ({} triangles) {state}",
+                                submesh.name,
+                                submesh.indices.len() / 3
+                            );
+                        }
+                    }
+                    // B toggles the glow around bright and emissive surfaces
+                    if scancode == SDL_SCANCODE_B {
+                        bloom_enabled = !bloom_enabled;
+                        println!("Bloom {}", if bloom_enabled { "on" } else { "off" });
+                    }
+                    // G compares gamma-correct shading with treating colors as linear
+                    if scancode == SDL_SCANCODE_G {
+                        srgb = !srgb;
+                        set_srgb_output(srgb, [&line_uniforms, &scene_uniforms]);
+                        for material in &mut model.materials {
+                            if let Err(err) = material.upload(&options.textures, srgb) {
+                                eprintln!("Failed to reload textures: {err}");
+                            }
+                        }
+                        println!("sRGB {}", if srgb { "on" } else { "off" });
+                    }
                 }
             }
             let keystate = SDL_GetKeyboardState(std::ptr::null_mut());
@@ -297,7 +358,6 @@ fn main() {
                 distance * f32::sin(elevation),
                 distance * f32::sin(azimuth) * f32::cos(elevation),
             );
-            glUniform3f(camera_pos_uniform, camera_pos.x, camera_pos.y, camera_pos.z);
 
             let (mut window_w, mut window_h) = (0, 0);
             SDL_GetWindowSize(win, &mut window_w, &mut window_h);
@@ -314,29 +374,80 @@ fn main() {
             // otherwise I'd have to compute this multiplication for each vertex. Here I only
             // have to do it once for all vertices
             let transform = projection * view;
-            glUniformMatrix4fv(transform_uniform, 1, 0, transform.data.as_slice().as_ptr());
-
-            glActiveTexture(GL_TEXTURE0);
-            glBindTexture(GL_TEXTURE_2D, material.ambient_map);
-            glActiveTexture(GL_TEXTURE1);
-            glBindTexture(GL_TEXTURE_2D, material.diffuse_map);
-            glActiveTexture(GL_TEXTURE2);
-            glBindTexture(GL_TEXTURE_2D, material.specular_map);
-
-            glBindBuffer(GL_ARRAY_BUFFER, vbo);
-            glBufferData(
-                GL_ARRAY_BUFFER,
-                (mesh.len() * (4 * 8)) as isize,
-                mesh.as_ptr().cast(),
-                GL_STATIC_DRAW,
-            );
+            for uniforms in [&line_uniforms, &scene_uniforms] {
+                glUseProgram(uniforms.program);
+                glUniform3f(
+                    uniforms.camera_pos,
+                    camera_pos.x,
+                    camera_pos.y,
+                    camera_pos.z,
+                );
+                glUniformMatrix4fv(uniforms.transform, 1, 0, transform.data.as_slice().as_ptr());
+            }
+            glUseProgram(line_program);
+            glUniform2f(viewport_uniform, window_w as f32, window_h as f32);
+            glUniform1f(line_width_uniform, line_width);
 
+            if bloom_enabled {
+                bloom.begin(window_w, window_h);
+            }
             glClear(GL_COLOR_BUFFER_BIT | GL_DEPTH_BUFFER_BIT);
-            glDrawArrays(GL_TRIANGLES, 0, (3 * mesh.len()) as i32);
+            glPointSize(point_size);
+            let draw = |mode: PrimitiveType, range: &MaterialRange| {
+                let uniforms = if mode == GL_LINES {
+                    &line_uniforms
+                } else {
+                    &scene_uniforms
+                };
+                glUseProgram(uniforms.program);
+                // Lines and points have no surface to light, so they use the flat diffuse color
+                glUniform1i(uniforms.unlit, (mode != GL_TRIANGLES) as i32);
+                model.materials[range.material].bind(&uniforms.material);
+                glDrawElements(
+                    mode,
+                    range.indices.len() as i32,
+                    index_type,
+                    (range.indices.start * model.indices.size_of_index()) as *const _,
+                );
+            };
+            for submesh in model.submeshes.iter().filter(|submesh| submesh.visible) {
+                for (mode, ranges) in submesh.batches() {
+                    for range in ranges {
+                        if model.materials[range.material].desc.alpha_mode() != AlphaMode::Blend {
+                            draw(mode, range);
+                        }
+                    }
+                }
+            }
+
+            // Blended ranges go over everything opaque, farthest from the camera first. They
+            // still test against the depth buffer but don't write to it, so they never hide
+            // each other
+            let mut blended: Vec<_> = blended_ranges
+                .iter()
+                .filter(|blended| model.submeshes[blended.0].visible)
+                .collect();
+            blended.sort_by(|a, b| {
+                glm::distance(&camera_pos, &b.3).total_cmp(&glm::distance(&camera_pos, &a.3))
+            });
+            glEnable(GL_BLEND);
+            glDepthMask(0);
+            for &&(submesh, batch, range, _) in &blended {
+                let (mode, ranges) = model.submeshes[submesh].batches()[batch];
+                draw(mode, &ranges[range]);
+            }
+            glDepthMask(1);
+            glDisable(GL_BLEND);
+
+            if bloom_enabled {
+                bloom.finish();
+            }
 
             SDL_GL_SwapWindow(win);
         }
 
+        // Textures are deleted when the last model using them is dropped, which needs the context
+        drop(model);
         SDL_Quit()
     }
 }
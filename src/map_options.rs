@@ -0,0 +1,312 @@
+use std::path::Path;
+
+use crate::obj_error::{next_token, parse_float, ObjError, ObjErrorKind};
+
+/// The channel of a texture that `-imfchan` reads a scalar map from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    /// The alpha channel
+    Matte,
+    Luminance,
+    Depth,
+}
+
+impl Channel {
+    /// The letter `-imfchan` uses for the channel
+    pub fn letter(self) -> char {
+        match self {
+            Channel::Red => 'r',
+            Channel::Green => 'g',
+            Channel::Blue => 'b',
+            Channel::Matte => 'm',
+            Channel::Luminance => 'l',
+            Channel::Depth => 'z',
+        }
+    }
+
+    pub fn from_letter(letter: char) -> Option<Self> {
+        Some(match letter {
+            'r' => Channel::Red,
+            'g' => Channel::Green,
+            'b' => Channel::Blue,
+            'm' => Channel::Matte,
+            'l' => Channel::Luminance,
+            'z' => Channel::Depth,
+            _ => return None,
+        })
+    }
+
+    /// How the fragment shader's `sample_map` selects the channel
+    pub fn shader_index(self) -> i32 {
+        match self {
+            // Depth images keep their values in the first channel
+            Channel::Red | Channel::Depth => 0,
+            Channel::Green => 1,
+            Channel::Blue => 2,
+            Channel::Matte => 3,
+            Channel::Luminance => 4,
+        }
+    }
+}
+
+/// `-type` of a `refl` statement: either a sphere map or one face of a cube map
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReflectionType {
+    Sphere = 1,
+    CubeTop = 2,
+    CubeBottom = 3,
+    CubeFront = 4,
+    CubeBack = 5,
+    CubeLeft = 6,
+    CubeRight = 7,
+}
+
+impl ReflectionType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sphere" => ReflectionType::Sphere,
+            "cube_top" => ReflectionType::CubeTop,
+            "cube_bottom" => ReflectionType::CubeBottom,
+            "cube_front" => ReflectionType::CubeFront,
+            "cube_back" => ReflectionType::CubeBack,
+            "cube_left" => ReflectionType::CubeLeft,
+            "cube_right" => ReflectionType::CubeRight,
+            _ => return None,
+        })
+    }
+
+    pub fn from_index(index: u32) -> Option<Self> {
+        [
+            ReflectionType::Sphere,
+            ReflectionType::CubeTop,
+            ReflectionType::CubeBottom,
+            ReflectionType::CubeFront,
+            ReflectionType::CubeBack,
+            ReflectionType::CubeLeft,
+            ReflectionType::CubeRight,
+        ]
+        .into_iter()
+        .find(|&reflection_type| reflection_type as u32 == index)
+    }
+
+    /// The cube face in the order of GL's cube map targets, which go +x, -x, +y, -y, +z, -z.
+    /// Front faces +z and right faces +x. `None` for a sphere map
+    pub fn cube_face(self) -> Option<usize> {
+        match self {
+            ReflectionType::Sphere => None,
+            ReflectionType::CubeRight => Some(0),
+            ReflectionType::CubeLeft => Some(1),
+            ReflectionType::CubeTop => Some(2),
+            ReflectionType::CubeBottom => Some(3),
+            ReflectionType::CubeFront => Some(4),
+            ReflectionType::CubeBack => Some(5),
+        }
+    }
+}
+
+/// The options written before the file name of a `map_` statement
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapOptions {
+    /// `-s`: scales the texture coordinates
+    pub scale: [f32; 3],
+    /// `-o`: offsets the texture coordinates
+    pub offset: [f32; 3],
+    /// `-clamp on` stops the texture repeating outside 0 to 1
+    pub clamp: bool,
+    /// `-bm`: multiplies the values of a bump map
+    pub bump_multiplier: f32,
+    /// `-imfchan`: the channel to read, or `None` to use the whole color
+    pub channel: Option<Channel>,
+    /// `-mm`: added to and multiplied with every value of the texture
+    pub base_gain: [f32; 2],
+    /// `-type`, which only `refl` uses
+    pub reflection_type: Option<ReflectionType>,
+}
+
+impl Default for MapOptions {
+    fn default() -> Self {
+        MapOptions {
+            scale: [1.0, 1.0, 1.0],
+            offset: [0.0, 0.0, 0.0],
+            clamp: false,
+            bump_multiplier: 1.0,
+            channel: None,
+            base_gain: [0.0, 1.0],
+            reflection_type: None,
+        }
+    }
+}
+
+/// Splits the rest of a `map_` statement into its options and the file name, which may contain
+/// spaces
+pub fn parse_map<'a>(
+    line: &'a str,
+    path: &Path,
+    line_number: usize,
+    statement: &str,
+) -> Result<(MapOptions, &'a str), ObjError> {
+    let mut options = MapOptions::default();
+    let mut split = line.split_whitespace().skip(1).peekable();
+    let invalid =
+        |token: &str| ObjError::new(path, Some(line_number), token, ObjErrorKind::InvalidOption);
+
+    // Blending, color correction, boost, turbulence and resolution don't apply to rasterized
+    // textures, so those are only skipped over
+    while let Some(&option) = split.peek() {
+        match option {
+            "-clamp" | "-blendu" | "-blendv" | "-cc" => {
+                split.next();
+                let on = match next_token(&mut split, path, line_number, option)? {
+                    "on" => true,
+                    "off" => false,
+                    token => return Err(invalid(token)),
+                };
+                if option == "-clamp" {
+                    options.clamp = on;
+                }
+            }
+            "-bm" | "-boost" | "-texres" => {
+                split.next();
+                let value = parse_float(&mut split, path, line_number, option)?;
+                if option == "-bm" {
+                    options.bump_multiplier = value;
+                }
+            }
+            "-mm" => {
+                split.next();
+                options.base_gain = [
+                    parse_float(&mut split, path, line_number, option)?,
+                    parse_float(&mut split, path, line_number, option)?,
+                ];
+            }
+            "-imfchan" => {
+                split.next();
+                let token = next_token(&mut split, path, line_number, option)?;
+                let mut letters = token.chars();
+                let channel = letters
+                    .next()
+                    .filter(|_| letters.next().is_none())
+                    .and_then(Channel::from_letter)
+                    .ok_or_else(|| invalid(token))?;
+                options.channel = Some(channel);
+            }
+            "-type" => {
+                split.next();
+                let token = next_token(&mut split, path, line_number, option)?;
+                let reflection_type =
+                    ReflectionType::from_name(token).ok_or_else(|| invalid(token))?;
+                options.reflection_type = Some(reflection_type);
+            }
+            "-o" | "-s" | "-t" => {
+                split.next();
+                // One number is required and up to two more may follow, with omitted
+                // components keeping their defaults
+                let mut values = vec![parse_float(&mut split, path, line_number, option)?];
+                while values.len() < 3 {
+                    match split.peek().and_then(|token| token.parse().ok()) {
+                        Some(value) => {
+                            split.next();
+                            values.push(value);
+                        }
+                        None => break,
+                    }
+                }
+                match option {
+                    "-s" => options.scale[..values.len()].copy_from_slice(&values),
+                    "-o" => options.offset[..values.len()].copy_from_slice(&values),
+                    _ => {}
+                }
+            }
+            // Anything else starts the file name
+            _ => break,
+        }
+    }
+    // Take the file name from the original line so that spaces inside it are kept
+    let name = match split.next() {
+        Some(first) => {
+            let start = first.as_ptr() as usize - line.as_ptr() as usize;
+            line[start..].trim_end()
+        }
+        None => {
+            return Err(ObjError::new(
+                path,
+                Some(line_number),
+                statement,
+                ObjErrorKind::MissingValue,
+            ))
+        }
+    };
+    Ok((options, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<(MapOptions, &str), ObjError> {
+        let statement = line.split_whitespace().next().unwrap_or_default();
+        parse_map(line, Path::new("test.mtl"), 1, statement)
+    }
+
+    #[test]
+    fn options_before_a_name_with_spaces() {
+        let (options, name) = parse("map_Kd -s 2 2 1 -o 0.5 0 0 -clamp on file name.png").unwrap();
+        assert_eq!(name, "file name.png");
+        assert_eq!(
+            options,
+            MapOptions {
+                scale: [2.0, 2.0, 1.0],
+                offset: [0.5, 0.0, 0.0],
+                clamp: true,
+                ..MapOptions::default()
+            }
+        );
+    }
+
+    #[test]
+    fn omitted_components_keep_their_defaults() {
+        let (options, name) = parse("map_Kd -s 2 -mm 0.1 0.8 -blendu off 3.png").unwrap();
+        // A number after the options is still the file name once they've been read
+        assert_eq!(name, "3.png");
+        assert_eq!(options.scale, [2.0, 1.0, 1.0]);
+        assert_eq!(options.base_gain, [0.1, 0.8]);
+    }
+
+    #[test]
+    fn imfchan_and_bump_multiplier() {
+        let (options, name) = parse("bump -imfchan r -bm 0.5 height.png").unwrap();
+        assert_eq!(name, "height.png");
+        assert_eq!(options.channel, Some(Channel::Red));
+        assert_eq!(options.bump_multiplier, 0.5);
+        for token in ["rg", "x"] {
+            let err = parse(&format!("bump -imfchan {token} height.png")).unwrap_err();
+            assert!(matches!(err.kind, ObjErrorKind::InvalidOption));
+            assert_eq!(err.token, token);
+        }
+    }
+
+    #[test]
+    fn reflection_types() {
+        let (options, _) = parse("refl -type cube_top sky.png").unwrap();
+        assert_eq!(options.reflection_type, Some(ReflectionType::CubeTop));
+        assert_eq!(ReflectionType::CubeTop.cube_face(), Some(2));
+        let (options, _) = parse("refl -type sphere room.png").unwrap();
+        assert_eq!(options.reflection_type, Some(ReflectionType::Sphere));
+        let err = parse("refl -type cube_inside sky.png").unwrap_err();
+        assert!(matches!(err.kind, ObjErrorKind::InvalidOption));
+    }
+
+    #[test]
+    fn missing_values() {
+        let err = parse("map_Kd -clamp on").unwrap_err();
+        assert!(matches!(err.kind, ObjErrorKind::MissingValue));
+        assert_eq!(err.token, "map_Kd");
+        let err = parse("map_Kd -clamp").unwrap_err();
+        assert!(matches!(err.kind, ObjErrorKind::MissingValue));
+        let err = parse("map_Kd -clamp maybe file.png").unwrap_err();
+        assert!(matches!(err.kind, ObjErrorKind::InvalidOption));
+    }
+}
@@ -1,229 +1,761 @@
 use gl33::global_loader::*;
 use gl33::*;
-use image::ImageReader;
-use std::{fs::File, io::Read};
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
-pub struct Material {
+use crate::asset_path;
+use crate::map_options::{parse_map, Channel, MapOptions, ReflectionType};
+use crate::obj_error::{parse_float, parse_float3, rest_of_line, ObjError, ObjErrorKind};
+use crate::texture_cache::{Texture, TextureCache};
+
+/// How a material's bump map changes the surface normal
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BumpMode {
+    #[default]
+    None = 0,
+    /// A tangent-space normal map, from `norm` or a color `bump` image
+    Normal = 1,
+    /// A height map, from a grayscale `bump` image or one read through `-imfchan`
+    Height = 2,
+    /// A `bump` image without `-imfchan`, which becomes `Height` or `Normal` once the image is
+    /// decoded
+    Auto = 3,
+}
+
+/// How a material's opacity is drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode {
+    Opaque = 0,
+    /// Fragments are either kept or discarded, for cutouts such as leaves
+    Mask = 1,
+    /// Blended over what's behind, after every opaque surface is drawn
+    Blend = 2,
+}
+
+/// The 2D maps a material can have. Each one is bound to the texture unit of the same number
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapKind {
+    Ambient = 0,
+    Diffuse = 1,
+    Specular = 2,
+    /// From `map_Bump`, `bump` or `norm`
+    Bump = 3,
+    Roughness = 4,
+    Metallic = 5,
+    /// From `map_d`, multiplied with the opacity
+    Alpha = 6,
+    /// From `map_Ke`, multiplied with the emissive color, which is white if only the map is given
+    Emissive = 7,
+    /// From `refl -type sphere`: the surroundings as seen in a mirrored ball, looking down -z
+    Sphere = 8,
+}
+
+pub const MAP_COUNT: usize = 9;
+
+/// The texture unit of the cube map, after every 2D map
+const CUBE_MAP_UNIT: u32 = MAP_COUNT as u32;
+
+impl MapKind {
+    pub const ALL: [MapKind; MAP_COUNT] = [
+        MapKind::Ambient,
+        MapKind::Diffuse,
+        MapKind::Specular,
+        MapKind::Bump,
+        MapKind::Roughness,
+        MapKind::Metallic,
+        MapKind::Alpha,
+        MapKind::Emissive,
+        MapKind::Sphere,
+    ];
+
+    /// The prefix of the map's uniforms in the fragment shader's `material` struct
+    fn uniform_name(self) -> &'static str {
+        match self {
+            MapKind::Ambient => "ambient",
+            MapKind::Diffuse => "diffuse",
+            MapKind::Specular => "specular",
+            MapKind::Bump => "bump",
+            MapKind::Roughness => "roughness",
+            MapKind::Metallic => "metallic",
+            MapKind::Alpha => "alpha",
+            MapKind::Emissive => "emissive",
+            MapKind::Sphere => "sphere",
+        }
+    }
+
+    /// Whether the map holds colors, which are stored as sRGB, rather than data that's used as is
+    pub fn is_color(self) -> bool {
+        matches!(
+            self,
+            MapKind::Ambient | MapKind::Diffuse | MapKind::Emissive | MapKind::Sphere
+        )
+    }
+
+    /// Whether a missing map is replaced by plain white, which leaves whatever it multiplies
+    /// unchanged. Bump and sphere maps are switched off instead
+    pub fn has_fallback(self) -> bool {
+        !matches!(self, MapKind::Bump | MapKind::Sphere)
+    }
+}
+
+/// A material as an MTL file describes it: plain data that can be parsed, compared and cached
+/// without an OpenGL context. `Material::new` and `Material::upload` turn it into textures
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialDesc {
     pub ambient: [f32; 3],
     pub diffuse: [f32; 3],
     pub specular: [f32; 3],
     pub shininess: f32,
-    pub ambient_map: u32,
-    pub diffuse_map: u32,
-    pub specular_map: u32,
+    /// Light given off by the surface itself, from `Ke`
+    pub emissive: [f32; 3],
+    /// From `d`, or `1 - Tr`. 1 is fully opaque
+    pub opacity: f32,
+    /// The index of refraction, from `Ni`
+    pub optical_density: f32,
+    /// The `illum` model: 0 is flat color, 1 is diffuse only and 2 and above are Blinn-Phong
+    pub illumination: i32,
+    /// Whether any key of the PBR extension was given, which shades the material with the
+    /// metallic-roughness model instead of Blinn-Phong
+    pub pbr: bool,
+    /// `Pr`
+    pub roughness: f32,
+    /// `Pm`
+    pub metallic: f32,
+    /// `Ps`
+    pub sheen: f32,
+    /// `Pc`, the strength of a clear coat layer over the surface
+    pub clearcoat: f32,
+    /// `Pcr`
+    pub clearcoat_roughness: f32,
+    /// `aniso`, from 0 for round highlights to 1 for highlights stretched along the tangent
+    pub anisotropy: f32,
+    pub bump_mode: BumpMode,
+    /// The image file of each map, indexed by `MapKind`, or `None` where the map wasn't given
+    pub map_files: [Option<PathBuf>; MAP_COUNT],
+    /// The image files of the cube map's faces, in the order of `ReflectionType::cube_face`
+    pub cube_files: [Option<PathBuf>; 6],
+    /// The options given with each map in `map_files`
+    pub map_options: [MapOptions; MAP_COUNT],
 }
 
-impl Default for Material {
+impl Default for MaterialDesc {
     fn default() -> Self {
-        Material {
+        MaterialDesc {
             ambient: [0.1, 0.1, 0.1],
             diffuse: [0.8, 0.8, 0.8],
             specular: [0.5, 0.5, 0.5],
             shininess: 32.0,
-            ambient_map: 0,
-            diffuse_map: 0,
-            specular_map: 0,
+            emissive: [0.0, 0.0, 0.0],
+            opacity: 1.0,
+            optical_density: 1.0,
+            illumination: 2,
+            pbr: false,
+            roughness: 0.5,
+            metallic: 0.0,
+            sheen: 0.0,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.0,
+            anisotropy: 0.0,
+            bump_mode: BumpMode::None,
+            map_files: Default::default(),
+            cube_files: Default::default(),
+            map_options: Default::default(),
         }
     }
 }
 
-pub fn get_material(filepath: &str, material_name: &str) -> Material {
-    let mut file = File::open(filepath).expect(format!("Couldn't find file {filepath}").as_str());
-    let mut obj = String::default();
-    let _ = file.read_to_string(&mut obj);
-    drop(file);
+impl MaterialDesc {
+    /// How the fragment shader reflects the surroundings: 0 for not at all, 1 with the sphere map
+    /// and 2 with the cube map
+    fn reflection_mode(&self) -> i32 {
+        if self.cube_files.iter().any(Option::is_some) {
+            2
+        } else if self.map_files[MapKind::Sphere as usize].is_some() {
+            1
+        } else {
+            0
+        }
+    }
 
-    let mut lines = obj.lines();
-    let mut found_material = false;
-    while let Some(line) = lines.next() {
-        if line == format!("newmtl {material_name}") {
-            found_material = true;
-            break;
+    /// A material with `d` below 1 is blended. Otherwise an alpha map makes it a cutout
+    pub fn alpha_mode(&self) -> AlphaMode {
+        if self.opacity < 1.0 {
+            AlphaMode::Blend
+        } else if self.map_files[MapKind::Alpha as usize].is_some() {
+            AlphaMode::Mask
+        } else {
+            AlphaMode::Opaque
         }
     }
 
-    let mut material = if found_material {
-        let mut ambient = [0.0, 0.0, 0.0];
-        let mut diffuse = [1.0, 1.0, 1.0];
-        let mut specular = [1.0, 1.0, 1.0];
-        let mut shininess = 0.0;
-        let mut ambient_map = 0;
-        let mut diffuse_map = 0;
-        let mut specular_map = 0;
-        'line_iter: for line in lines {
-            let mut split = line.split(" ");
-            match split.next() {
-                Some("newmtl") => break 'line_iter,
-                Some("Ka") => {
-                    ambient = [
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                    ]
-                }
-                Some("Kd") => {
-                    diffuse = [
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                    ]
+    fn set_map(&mut self, kind: MapKind, file: PathBuf, options: MapOptions) {
+        self.map_files[kind as usize] = Some(file);
+        self.map_options[kind as usize] = options;
+    }
+}
+
+/// A material with its maps uploaded as GL textures, ready to be bound for drawing
+#[derive(Clone, Default)]
+pub struct Material {
+    pub desc: MaterialDesc,
+    /// The texture of each map, indexed by `MapKind`. `None` until `upload`, and afterwards for
+    /// maps the material doesn't have
+    pub maps: [Option<Rc<Texture>>; MAP_COUNT],
+    /// A cube map made from the `refl -type cube_*` faces
+    pub cube_map: Option<Rc<Texture>>,
+    /// `desc.bump_mode`, with `Auto` decided by the image once it's uploaded
+    bump_mode: BumpMode,
+    /// `desc.map_options`, with the alpha map's channel decided by the image once it's uploaded
+    map_options: [MapOptions; MAP_COUNT],
+}
+
+/// Gets the location of a uniform, panicking if the shader doesn't declare it
+pub unsafe fn get_uniform(program: u32, name: &CStr) -> i32 {
+    let location = glGetUniformLocation(program, name.as_ptr().cast());
+    assert_ne!(location, -1, "Uniform {name:?} does not exist");
+    location
+}
+
+/// Uniform locations of one `MapOptions` struct in the fragment shader
+struct MapUniforms {
+    transform: i32,
+    channel: i32,
+    base_gain: i32,
+}
+
+impl MapUniforms {
+    unsafe fn new(program: u32, map: &str) -> Self {
+        let uniform = |field: &str| {
+            let name = CString::new(format!("material.{map}_options.{field}")).unwrap();
+            get_uniform(program, &name)
+        };
+        MapUniforms {
+            transform: uniform("transform"),
+            channel: uniform("channel"),
+            base_gain: uniform("base_gain"),
+        }
+    }
+
+    unsafe fn set(&self, options: &MapOptions) {
+        glUniform4f(
+            self.transform,
+            options.scale[0],
+            options.scale[1],
+            options.offset[0],
+            options.offset[1],
+        );
+        glUniform1i(
+            self.channel,
+            options.channel.map_or(-1, |channel| channel.shader_index()),
+        );
+        glUniform2f(self.base_gain, options.base_gain[0], options.base_gain[1]);
+    }
+}
+
+/// Uniform locations of the `material` struct in the fragment shader
+pub struct MaterialUniforms {
+    ambient: i32,
+    diffuse: i32,
+    specular: i32,
+    shininess: i32,
+    emissive: i32,
+    illumination: i32,
+    bump_mode: i32,
+    bump_multiplier: i32,
+    pbr: i32,
+    roughness: i32,
+    metallic: i32,
+    sheen: i32,
+    clearcoat: i32,
+    clearcoat_roughness: i32,
+    anisotropy: i32,
+    opacity: i32,
+    alpha_mode: i32,
+    reflection_mode: i32,
+    /// The options of each map, indexed by `MapKind`
+    map_options: [MapUniforms; MAP_COUNT],
+}
+
+impl MaterialUniforms {
+    /// Looks up the material uniforms and assigns each map its texture unit. The program must be
+    /// in use
+    pub unsafe fn new(program: u32) -> Self {
+        for kind in MapKind::ALL {
+            let name = CString::new(format!("material.{}_map", kind.uniform_name())).unwrap();
+            glUniform1i(get_uniform(program, &name), kind as i32);
+        }
+        glUniform1i(
+            get_uniform(program, c"material.cube_map"),
+            CUBE_MAP_UNIT as i32,
+        );
+
+        MaterialUniforms {
+            ambient: get_uniform(program, c"material.ambient"),
+            diffuse: get_uniform(program, c"material.diffuse"),
+            specular: get_uniform(program, c"material.specular"),
+            shininess: get_uniform(program, c"material.shininess"),
+            emissive: get_uniform(program, c"material.emissive"),
+            illumination: get_uniform(program, c"material.illum"),
+            bump_mode: get_uniform(program, c"material.bump_mode"),
+            bump_multiplier: get_uniform(program, c"material.bump_multiplier"),
+            pbr: get_uniform(program, c"material.pbr"),
+            roughness: get_uniform(program, c"material.roughness"),
+            metallic: get_uniform(program, c"material.metallic"),
+            sheen: get_uniform(program, c"material.sheen"),
+            clearcoat: get_uniform(program, c"material.clearcoat"),
+            clearcoat_roughness: get_uniform(program, c"material.clearcoat_roughness"),
+            anisotropy: get_uniform(program, c"material.anisotropy"),
+            opacity: get_uniform(program, c"material.opacity"),
+            alpha_mode: get_uniform(program, c"material.alpha_mode"),
+            reflection_mode: get_uniform(program, c"material.reflection_mode"),
+            map_options: MapKind::ALL.map(|kind| MapUniforms::new(program, kind.uniform_name())),
+        }
+    }
+}
+
+impl Material {
+    /// A material whose maps aren't uploaded yet
+    pub fn new(desc: MaterialDesc) -> Self {
+        Material {
+            bump_mode: desc.bump_mode,
+            map_options: desc.map_options.clone(),
+            desc,
+            maps: Default::default(),
+            cube_map: None,
+        }
+    }
+
+    /// Gets the textures of the images named in the description, uploading those that aren't
+    /// cached yet. With `srgb`, color maps are decoded to linear values when sampled. Also settles
+    /// what depends on the images' contents: whether a `bump` map holds heights or normals, and
+    /// which channel an alpha map is read from
+    pub fn upload(&mut self, textures: &TextureCache, srgb: bool) -> Result<(), ObjError> {
+        for kind in MapKind::ALL {
+            let Some(file) = &self.desc.map_files[kind as usize] else {
+                continue;
+            };
+            let options = &mut self.map_options[kind as usize];
+            let texture = textures.image(file, options.clamp, srgb && kind.is_color())?;
+            match kind {
+                // `bump` traditionally means a height map, but exporters also write normal maps
+                // under it, so the image decides
+                MapKind::Bump if self.bump_mode == BumpMode::Auto => {
+                    self.bump_mode = if texture.grayscale {
+                        BumpMode::Height
+                    } else {
+                        BumpMode::Normal
+                    };
                 }
-                Some("Ks") => {
-                    specular = [
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                        split.next().unwrap().parse::<f32>().unwrap(),
-                    ]
+                // Alpha maps are often the diffuse texture again, so read its alpha channel if it
+                // has one
+                MapKind::Alpha if options.channel.is_none() => {
+                    options.channel = Some(if texture.has_alpha {
+                        Channel::Matte
+                    } else {
+                        Channel::Luminance
+                    });
                 }
-                Some("Ns") => shininess = split.next().unwrap().parse::<f32>().unwrap(),
-                Some("map_Ka") => unsafe {
-                    // Load texture
-                    let ambient_img =
-                        ImageReader::open(format!("texture/{}", split.next().unwrap()))
-                            .expect("Couldn't find container")
-                            .decode()
-                            .unwrap();
-                    let ambient_img = ambient_img.flipv();
-                    let ambient_bytes = ambient_img.as_bytes();
-
-                    let mut ambient: u32 = 0;
-                    glGenTextures(1, &mut ambient);
-                    glBindTexture(GL_TEXTURE_2D, ambient);
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, GL_CLAMP_TO_BORDER.0 as _);
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, GL_CLAMP_TO_BORDER.0 as _);
-                    glTexParameteri(
-                        GL_TEXTURE_2D,
-                        GL_TEXTURE_MIN_FILTER,
-                        GL_LINEAR_MIPMAP_LINEAR.0 as _,
-                    );
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_LINEAR.0 as _);
-                    glTexImage2D(
-                        GL_TEXTURE_2D,
-                        0,
-                        GL_RGBA.0 as _,
-                        ambient_img.width() as _,
-                        ambient_img.height() as _,
-                        0,
-                        GL_RGBA,
-                        GL_UNSIGNED_BYTE,
-                        ambient_bytes.as_ptr() as _,
-                    );
-                    glGenerateMipmap(GL_TEXTURE_2D);
-                    ambient_map = ambient;
-                },
-                Some("map_Kd") => unsafe {
-                    // Load texture
-                    let diffuse_img =
-                        ImageReader::open(format!("texture/{}", split.next().unwrap()))
-                            .expect("Couldn't find container")
-                            .decode()
-                            .unwrap();
-                    let diffuse_img = diffuse_img.flipv();
-                    let diffuse_bytes = diffuse_img.as_bytes();
-
-                    let mut diffuse: u32 = 0;
-                    glGenTextures(1, &mut diffuse);
-                    glBindTexture(GL_TEXTURE_2D, diffuse);
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, GL_CLAMP_TO_BORDER.0 as _);
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, GL_CLAMP_TO_BORDER.0 as _);
-                    glTexParameteri(
-                        GL_TEXTURE_2D,
-                        GL_TEXTURE_MIN_FILTER,
-                        GL_LINEAR_MIPMAP_LINEAR.0 as _,
-                    );
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_LINEAR.0 as _);
-                    glTexImage2D(
-                        GL_TEXTURE_2D,
-                        0,
-                        GL_RGBA.0 as _,
-                        diffuse_img.width() as _,
-                        diffuse_img.height() as _,
-                        0,
-                        GL_RGBA,
-                        GL_UNSIGNED_BYTE,
-                        diffuse_bytes.as_ptr() as _,
-                    );
-                    glGenerateMipmap(GL_TEXTURE_2D);
-                    diffuse_map = diffuse;
-                },
-                Some("map_Ks") => unsafe {
-                    // Load specular map
-                    let specular_img =
-                        ImageReader::open(format!("texture/{}", split.next().unwrap()))
-                            .expect("Couldn't find container")
-                            .decode()
-                            .unwrap();
-                    let specular_img = specular_img.flipv();
-                    let specular_bytes = specular_img.as_bytes();
-
-                    let mut specular: u32 = 0;
-                    glGenTextures(1, &mut specular);
-                    glBindTexture(GL_TEXTURE_2D, specular);
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, GL_CLAMP_TO_BORDER.0 as _);
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, GL_CLAMP_TO_BORDER.0 as _);
-                    glTexParameteri(
-                        GL_TEXTURE_2D,
-                        GL_TEXTURE_MIN_FILTER,
-                        GL_LINEAR_MIPMAP_LINEAR.0 as _,
-                    );
-                    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_LINEAR.0 as _);
-                    glTexImage2D(
-                        GL_TEXTURE_2D,
-                        0,
-                        GL_RGBA.0 as _,
-                        specular_img.width() as _,
-                        specular_img.height() as _,
-                        0,
-                        GL_RGBA,
-                        GL_UNSIGNED_BYTE,
-                        specular_bytes.as_ptr() as _,
-                    );
-                    glGenerateMipmap(GL_TEXTURE_2D);
-                    specular_map = specular;
-                },
                 _ => {}
             }
+            self.maps[kind as usize] = Some(texture);
         }
-        Material {
-            ambient,
-            diffuse,
-            specular,
-            shininess,
-            ambient_map,
-            diffuse_map,
-            specular_map,
+        if self.desc.cube_files.iter().any(Option::is_some) {
+            self.cube_map = Some(textures.cube_map(&self.desc.cube_files, srgb)?);
         }
-    } else {
-        Material::default()
-    };
-
-    if material.ambient_map == 0 || material.diffuse_map == 0 || material.specular_map == 0 {
-        unsafe {
-            let mut white: u32 = 0;
-            glGenTextures(1, &mut white);
-            glBindTexture(GL_TEXTURE_2D, white);
-            glTexImage2D(
-                GL_TEXTURE_2D,
-                0,
-                GL_RGB.0 as _,
-                1,
-                1,
-                0,
-                GL_RGB,
-                GL_UNSIGNED_BYTE,
-                [255u8, 255u8, 255u8].as_ptr() as _,
-            );
-            if material.ambient_map == 0 {
-                material.ambient_map = white;
+        Ok(())
+    }
+
+    /// Uploads the material's parameters and binds its maps to their texture units
+    pub unsafe fn bind(&self, uniforms: &MaterialUniforms) {
+        let desc = &self.desc;
+        glUniform3f(
+            uniforms.ambient,
+            desc.ambient[0],
+            desc.ambient[1],
+            desc.ambient[2],
+        );
+        glUniform3f(
+            uniforms.diffuse,
+            desc.diffuse[0],
+            desc.diffuse[1],
+            desc.diffuse[2],
+        );
+        glUniform3f(
+            uniforms.specular,
+            desc.specular[0],
+            desc.specular[1],
+            desc.specular[2],
+        );
+        glUniform1f(uniforms.shininess, desc.shininess);
+        glUniform3f(
+            uniforms.emissive,
+            desc.emissive[0],
+            desc.emissive[1],
+            desc.emissive[2],
+        );
+        glUniform1i(uniforms.illumination, desc.illumination);
+        glUniform1i(uniforms.bump_mode, self.bump_mode as i32);
+        glUniform1f(
+            uniforms.bump_multiplier,
+            self.map_options[MapKind::Bump as usize].bump_multiplier,
+        );
+        glUniform1i(uniforms.pbr, desc.pbr as i32);
+        glUniform1f(uniforms.roughness, desc.roughness);
+        glUniform1f(uniforms.metallic, desc.metallic);
+        glUniform1f(uniforms.sheen, desc.sheen);
+        glUniform1f(uniforms.clearcoat, desc.clearcoat);
+        glUniform1f(uniforms.clearcoat_roughness, desc.clearcoat_roughness);
+        glUniform1f(uniforms.anisotropy, desc.anisotropy);
+        glUniform1f(uniforms.opacity, desc.opacity);
+        glUniform1i(uniforms.alpha_mode, desc.alpha_mode() as i32);
+        glUniform1i(uniforms.reflection_mode, desc.reflection_mode());
+        for (map, options) in uniforms.map_options.iter().zip(&self.map_options) {
+            map.set(options);
+        }
+
+        let id = |texture: &Option<Rc<Texture>>| texture.as_ref().map_or(0, |texture| texture.id);
+        for (unit, map) in self.maps.iter().enumerate() {
+            glActiveTexture(GLenum(GL_TEXTURE0.0 + unit as u32));
+            glBindTexture(GL_TEXTURE_2D, id(map));
+        }
+        glActiveTexture(GLenum(GL_TEXTURE0.0 + CUBE_MAP_UNIT));
+        glBindTexture(GL_TEXTURE_CUBE_MAP, id(&self.cube_map));
+    }
+}
+
+/// The materials defined by one MTL file, read in a single pass. A library can be loaded once and
+/// shared by every OBJ file that lists it
+pub struct MaterialLibrary {
+    path: PathBuf,
+    /// Materials in the order the file defines them
+    materials: Vec<(String, MaterialDesc)>,
+    /// Indices into `materials` by name
+    names: HashMap<String, usize>,
+}
+
+impl MaterialLibrary {
+    /// Parses an MTL file. Texture paths are resolved relative to the MTL file, then the search
+    /// paths, but the images aren't read until the materials are uploaded
+    pub fn load(filepath: &Path, search_paths: &[PathBuf]) -> Result<Self, ObjError> {
+        let mut mtl = String::default();
+        File::open(filepath)
+            .and_then(|mut file| file.read_to_string(&mut mtl))
+            .map_err(|err| ObjError::new(filepath, None, "", ObjErrorKind::Io(err)))?;
+
+        let mut library = MaterialLibrary {
+            path: filepath.to_path_buf(),
+            materials: vec![],
+            names: HashMap::new(),
+        };
+        // Statements before the first `newmtl` don't belong to any material
+        let mut material: Option<(String, MaterialDesc)> = None;
+        // Whether the current material has a `Ke`, which an emissive map is multiplied with
+        let mut has_emissive_color = false;
+        for (line_index, line) in mtl.lines().enumerate() {
+            let line_number = line_index + 1;
+            let mut split = line.split_whitespace();
+            let statement = split.next();
+            if let Some(statement @ "newmtl") = statement {
+                library.insert(material.take());
+                let name = rest_of_line(line, filepath, line_number, statement)?;
+                // The MTL format's own defaults, which differ from those of a missing material
+                let defaults = MaterialDesc {
+                    ambient: [0.0, 0.0, 0.0],
+                    diffuse: [1.0, 1.0, 1.0],
+                    specular: [1.0, 1.0, 1.0],
+                    shininess: 0.0,
+                    ..MaterialDesc::default()
+                };
+                material = Some((name.to_string(), defaults));
+                has_emissive_color = false;
+                continue;
             }
-            if material.diffuse_map == 0 {
-                material.diffuse_map = white;
+            let Some((_, material)) = &mut material else {
+                continue;
+            };
+            match statement {
+                Some(statement @ "Ka") => {
+                    material.ambient = parse_float3(&mut split, filepath, line_number, statement)?
+                }
+                Some(statement @ "Kd") => {
+                    material.diffuse = parse_float3(&mut split, filepath, line_number, statement)?
+                }
+                Some(statement @ "Ks") => {
+                    material.specular = parse_float3(&mut split, filepath, line_number, statement)?
+                }
+                Some(statement @ "Ns") => {
+                    material.shininess = parse_float(&mut split, filepath, line_number, statement)?
+                }
+                Some(statement @ "Ke") => {
+                    material.emissive = parse_float3(&mut split, filepath, line_number, statement)?;
+                    has_emissive_color = true;
+                }
+                Some(statement @ "d") => {
+                    material.opacity = parse_float(&mut split, filepath, line_number, statement)?
+                }
+                Some(statement @ "Tr") => {
+                    material.opacity =
+                        1.0 - parse_float(&mut split, filepath, line_number, statement)?
+                }
+                Some(statement @ "Ni") => {
+                    material.optical_density =
+                        parse_float(&mut split, filepath, line_number, statement)?
+                }
+                Some(statement @ "illum") => {
+                    material.illumination =
+                        parse_float(&mut split, filepath, line_number, statement)? as i32
+                }
+                Some(statement @ ("Pr" | "Pm" | "Ps" | "Pc" | "Pcr" | "aniso")) => {
+                    let value = parse_float(&mut split, filepath, line_number, statement)?;
+                    let parameter = match statement {
+                        "Pr" => &mut material.roughness,
+                        "Pm" => &mut material.metallic,
+                        "Ps" => &mut material.sheen,
+                        "Pc" => &mut material.clearcoat,
+                        "Pcr" => &mut material.clearcoat_roughness,
+                        _ => &mut material.anisotropy,
+                    };
+                    *parameter = value;
+                    material.pbr = true;
+                }
+                Some(
+                    statement @ ("map_Ka" | "map_Kd" | "map_Ks" | "map_Pr" | "map_Pm" | "map_d"
+                    | "map_Ke"),
+                ) => {
+                    let (options, name) = parse_map(line, filepath, line_number, statement)?;
+                    let kind = match statement {
+                        "map_Ka" => MapKind::Ambient,
+                        "map_Kd" => MapKind::Diffuse,
+                        "map_Ks" => MapKind::Specular,
+                        "map_Pr" => MapKind::Roughness,
+                        "map_Pm" => MapKind::Metallic,
+                        "map_d" => MapKind::Alpha,
+                        _ => MapKind::Emissive,
+                    };
+                    let file = asset_path::resolve(name, filepath, search_paths);
+                    material.set_map(kind, file, options);
+                    material.pbr |= matches!(kind, MapKind::Roughness | MapKind::Metallic);
+                    // A map on its own glows at full strength, rather than being multiplied by
+                    // the black default
+                    if kind == MapKind::Emissive && !has_emissive_color {
+                        material.emissive = [1.0, 1.0, 1.0];
+                    }
+                }
+                Some(statement @ "refl") => {
+                    let (options, name) = parse_map(line, filepath, line_number, statement)?;
+                    let file = asset_path::resolve(name, filepath, search_paths);
+                    // Without a `-type` the map is taken to be a sphere map
+                    let face = options.reflection_type.and_then(ReflectionType::cube_face);
+                    match face {
+                        Some(face) => material.cube_files[face] = Some(file),
+                        None => material.set_map(MapKind::Sphere, file, options),
+                    }
+                }
+                Some(statement @ ("map_Bump" | "bump" | "norm")) => {
+                    let (options, name) = parse_map(line, filepath, line_number, statement)?;
+                    let file = asset_path::resolve(name, filepath, search_paths);
+                    material.bump_mode = if statement == "norm" {
+                        BumpMode::Normal
+                    } else if options.channel.is_some() {
+                        BumpMode::Height
+                    } else {
+                        BumpMode::Auto
+                    };
+                    material.set_map(MapKind::Bump, file, options);
+                }
+                _ => {}
             }
-            if material.specular_map == 0 {
-                material.specular_map = white;
+        }
+        library.insert(material);
+        Ok(library)
+    }
+
+    /// Adds a finished material. If a name is defined twice, the first definition wins
+    fn insert(&mut self, material: Option<(String, MaterialDesc)>) {
+        if let Some((name, material)) = material {
+            if !self.names.contains_key(&name) {
+                self.names.insert(name.clone(), self.materials.len());
+                self.materials.push((name, material));
             }
         }
     }
 
-    material
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MaterialDesc> {
+        self.names.get(name).map(|&index| &self.materials[index].1)
+    }
+
+    /// Visits the materials in the order the file defines them
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MaterialDesc)> {
+        self.materials
+            .iter()
+            .map(|(name, material)| (name.as_str(), material))
+    }
+}
+
+impl<'a> IntoIterator for &'a MaterialLibrary {
+    type Item = (&'a str, &'a MaterialDesc);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Writes an MTL file to a directory of its own and loads it. Also returns the directory, which
+    /// texture paths resolve against since none of the images exist
+    fn load(name: &str, mtl: &str) -> (MaterialLibrary, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mtl-test-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.mtl");
+        fs::write(&path, mtl).unwrap();
+        let library = MaterialLibrary::load(&path, &[]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (library, dir)
+    }
+
+    #[test]
+    fn colors_and_scalars() {
+        let (library, _) = load(
+            "colors",
+            "Kd 0 0 0\n\
+             newmtl plastic\n\
+             Ka 0.1 0.2 0.3\n\
+             Kd 0.5 0.25 1\n\
+             Ks 0 0 0\n\
+             Ns 96\n\
+             Ke 0.5 0.5 0\n\
+             d 0.4\n\
+             Ni 1.5\n\
+             illum 1\n\
+             newmtl glass\n\
+             Tr 0.75\n",
+        );
+        // The `Kd` before the first `newmtl` doesn't belong to any material
+        assert_eq!(library.iter().count(), 2);
+        let plastic = library.get("plastic").unwrap();
+        assert_eq!(plastic.ambient, [0.1, 0.2, 0.3]);
+        assert_eq!(plastic.diffuse, [0.5, 0.25, 1.0]);
+        assert_eq!(plastic.specular, [0.0, 0.0, 0.0]);
+        assert_eq!(plastic.shininess, 96.0);
+        assert_eq!(plastic.emissive, [0.5, 0.5, 0.0]);
+        assert_eq!(plastic.opacity, 0.4);
+        assert_eq!(plastic.optical_density, 1.5);
+        assert_eq!(plastic.illumination, 1);
+        assert!(!plastic.pbr);
+        // `Tr` is the opposite of `d`
+        assert_eq!(library.get("glass").unwrap().opacity, 0.25);
+    }
+
+    #[test]
+    fn pbr_keys() {
+        let (library, _) = load(
+            "pbr",
+            "newmtl metal\nPr 0.2\nPm 1\nPs 0.3\nPc 0.4\nPcr 0.05\naniso 0.6\n\
+             newmtl rough\nmap_Pr rough.png\n",
+        );
+        let metal = library.get("metal").unwrap();
+        assert!(metal.pbr);
+        assert_eq!(metal.roughness, 0.2);
+        assert_eq!(metal.metallic, 1.0);
+        assert_eq!(metal.sheen, 0.3);
+        assert_eq!(metal.clearcoat, 0.4);
+        assert_eq!(metal.clearcoat_roughness, 0.05);
+        assert_eq!(metal.anisotropy, 0.6);
+        // A PBR map on its own switches the model too
+        assert!(library.get("rough").unwrap().pbr);
+    }
+
+    #[test]
+    fn maps() {
+        let (library, dir) = load(
+            "maps",
+            "newmtl panel\n\
+             map_Kd -s 2 2 1 -o 0.5 0 0 -clamp on file name.png\n\
+             map_Ke glow.png\n\
+             bump -imfchan r -bm 2 height.png\n\
+             refl -type cube_top sky top.png\n\
+             refl -type cube_bottom sky bottom.png\n\
+             newmtl mirror\n\
+             Ke 0 0 0\n\
+             map_Ke glow.png\n\
+             refl room.png\n\
+             norm normal.png\n",
+        );
+        let panel = library.get("panel").unwrap();
+        let diffuse = MapKind::Diffuse as usize;
+        assert_eq!(panel.map_files[diffuse], Some(dir.join("file name.png")));
+        assert_eq!(
+            panel.map_options[diffuse],
+            MapOptions {
+                scale: [2.0, 2.0, 1.0],
+                offset: [0.5, 0.0, 0.0],
+                clamp: true,
+                ..MapOptions::default()
+            }
+        );
+        // An emissive map without `Ke` isn't multiplied by black
+        assert_eq!(panel.emissive, [1.0, 1.0, 1.0]);
+        assert_eq!(panel.bump_mode, BumpMode::Height);
+        let bump = &panel.map_options[MapKind::Bump as usize];
+        assert_eq!(bump.channel, Some(Channel::Red));
+        assert_eq!(bump.bump_multiplier, 2.0);
+        assert_eq!(panel.cube_files[2], Some(dir.join("sky top.png")));
+        assert_eq!(panel.cube_files[3], Some(dir.join("sky bottom.png")));
+        assert_eq!(panel.map_files[MapKind::Sphere as usize], None);
+        assert_eq!(panel.reflection_mode(), 2);
+
+        let mirror = library.get("mirror").unwrap();
+        assert_eq!(mirror.emissive, [0.0, 0.0, 0.0]);
+        assert_eq!(
+            mirror.map_files[MapKind::Sphere as usize],
+            Some(dir.join("room.png"))
+        );
+        assert_eq!(mirror.reflection_mode(), 1);
+        assert_eq!(mirror.bump_mode, BumpMode::Normal);
+    }
+
+    #[test]
+    fn newmtl_defaults_differ_from_a_missing_material() {
+        let (library, _) = load(
+            "defaults",
+            "newmtl empty\nnewmtl second\nnewmtl empty\nKd 0 0 0\n",
+        );
+        let empty = library.get("empty").unwrap();
+        assert_eq!(empty.ambient, [0.0, 0.0, 0.0]);
+        assert_eq!(empty.diffuse, [1.0, 1.0, 1.0]);
+        assert_eq!(empty.specular, [1.0, 1.0, 1.0]);
+        assert_eq!(empty.shininess, 0.0);
+        assert_ne!(*empty, MaterialDesc::default());
+        assert!(library.get("missing").is_none());
+        // The first definition of a name wins, and the file order is kept
+        let names: Vec<&str> = library.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["empty", "second"]);
+    }
+
+    #[test]
+    fn errors_point_at_the_line() {
+        let dir = std::env::temp_dir().join(format!("mtl-test-{}-errors", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.mtl");
+        fs::write(&path, "newmtl a\nKd 1 1\n").unwrap();
+        let err = MaterialLibrary::load(&path, &[]).err().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(err.line, Some(2));
+        assert!(matches!(err.kind, ObjErrorKind::MissingValue));
+    }
 }
@@ -0,0 +1,447 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::map_options::{Channel, MapOptions, ReflectionType};
+use crate::material_gen::{BumpMode, Material, MaterialDesc};
+use crate::vertex_gen::{Indices, LoadOptions, MaterialRange, Model, Submesh, Vertex};
+
+const MAGIC: &[u8; 4] = b"OBJC";
+/// Bumped whenever the layout changes, so that old caches are rebuilt instead of misread
+const VERSION: u32 = 9;
+
+/// The cache file of one OBJ file loaded with one set of options
+pub struct MeshCache {
+    path: PathBuf,
+}
+
+/// What a file looked like when the cache was written
+struct Dependency {
+    path: PathBuf,
+    len: u64,
+    /// Nanoseconds since the Unix epoch, or 0 if the platform doesn't report it
+    modified: u64,
+    hash: u64,
+}
+
+impl Dependency {
+    fn new(path: &Path) -> io::Result<Self> {
+        let (len, modified) = stat(path)?;
+        Ok(Dependency {
+            path: path.to_path_buf(),
+            len,
+            modified,
+            hash: hash_file(path)?,
+        })
+    }
+
+    /// Checks the file against the cache. The contents are only hashed again if the size or
+    /// modification time changed, so touching a file without editing it keeps the cache valid
+    fn is_current(&self) -> bool {
+        match stat(&self.path) {
+            Ok((len, modified)) if len == self.len && modified == self.modified => true,
+            Ok((len, _)) if len == self.len => {
+                hash_file(&self.path).is_ok_and(|hash| hash == self.hash)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn stat(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64);
+    Ok((metadata.len(), modified))
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    fs::read(path)?.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+impl MeshCache {
+    /// Names the cache file after the OBJ file's path and the options that change the loaded
+    /// model, so each combination gets its own entry
+    pub fn new(cache_dir: &Path, filepath: &Path, options: &LoadOptions) -> Self {
+        let mut hasher = DefaultHasher::new();
+        fs::canonicalize(filepath)
+            .unwrap_or_else(|_| filepath.to_path_buf())
+            .hash(&mut hasher);
+        options.crease_angle.map(f32::to_bits).hash(&mut hasher);
+        options.search_paths.hash(&mut hasher);
+        options.tessellation.hash(&mut hasher);
+        MeshCache {
+            path: cache_dir.join(format!("{:016x}.objcache", hasher.finish())),
+        }
+    }
+
+    /// Reads the cached model, if there is one and none of the files it was built from have
+    /// changed. Material maps aren't loaded, only their file names
+    pub fn load(&self) -> Option<Model> {
+        let bytes = fs::read(&self.path).ok()?;
+        let mut reader = Reader { bytes: &bytes };
+        if reader.take(4)? != MAGIC || reader.u32()? != VERSION {
+            return None;
+        }
+
+        for _ in 0..reader.u32()? {
+            let dependency = Dependency {
+                path: reader.path()?,
+                len: reader.u64()?,
+                modified: reader.u64()?,
+                hash: reader.u64()?,
+            };
+            if !dependency.is_current() {
+                return None;
+            }
+        }
+
+        let vertex_count = reader.u64()? as usize;
+        let floats = reader.take(vertex_count.checked_mul(size_of::<Vertex>())?)?;
+        let vertices = floats
+            .chunks_exact(size_of::<Vertex>())
+            .map(|vertex| {
+                let mut attributes = Vertex::default();
+                for (attribute, bytes) in attributes.iter_mut().zip(vertex.chunks_exact(4)) {
+                    *attribute = f32::from_le_bytes(bytes.try_into().unwrap());
+                }
+                attributes
+            })
+            .collect();
+
+        let size_of_index = reader.u32()?;
+        let index_count = reader.u64()? as usize;
+        let index_bytes = reader.take(index_count.checked_mul(size_of_index as usize)?)?;
+        let indices = match size_of_index {
+            2 => Indices::U16(
+                index_bytes
+                    .chunks_exact(2)
+                    .map(|index| u16::from_le_bytes([index[0], index[1]]))
+                    .collect(),
+            ),
+            4 => Indices::U32(
+                index_bytes
+                    .chunks_exact(4)
+                    .map(|index| u32::from_le_bytes(index.try_into().unwrap()))
+                    .collect(),
+            ),
+            _ => return None,
+        };
+
+        let submeshes = (0..reader.u32()?)
+            .map(|_| {
+                Some(Submesh {
+                    name: reader.string()?,
+                    indices: reader.u64()? as usize..reader.u64()? as usize,
+                    ranges: reader.ranges()?,
+                    lines: reader.ranges()?,
+                    points: reader.ranges()?,
+                    visible: reader.u32()? != 0,
+                })
+            })
+            .collect::<Option<_>>()?;
+
+        let materials = (0..reader.u32()?)
+            .map(|_| {
+                Some(Material::new(MaterialDesc {
+                    ambient: reader.f32x3()?,
+                    diffuse: reader.f32x3()?,
+                    specular: reader.f32x3()?,
+                    shininess: reader.f32()?,
+                    emissive: reader.f32x3()?,
+                    opacity: reader.f32()?,
+                    optical_density: reader.f32()?,
+                    illumination: reader.u32()? as i32,
+                    pbr: reader.u32()? != 0,
+                    roughness: reader.f32()?,
+                    metallic: reader.f32()?,
+                    sheen: reader.f32()?,
+                    clearcoat: reader.f32()?,
+                    clearcoat_roughness: reader.f32()?,
+                    anisotropy: reader.f32()?,
+                    bump_mode: match reader.u32()? {
+                        0 => BumpMode::None,
+                        1 => BumpMode::Normal,
+                        2 => BumpMode::Height,
+                        3 => BumpMode::Auto,
+                        _ => return None,
+                    },
+                    map_files: reader.array(Reader::map_file)?,
+                    cube_files: reader.array(Reader::map_file)?,
+                    map_options: reader.array(Reader::map_options)?,
+                }))
+            })
+            .collect::<Option<_>>()?;
+
+        let model = Model {
+            vertices,
+            indices,
+            submeshes,
+            materials,
+        };
+        // A damaged file can still be the right length, so it has to be checked before anything
+        // indexes with it
+        is_consistent(&model).then_some(model)
+    }
+
+    /// Writes the model to the cache, along with the state of every file it was built from
+    pub fn store(&self, model: &Model, dependencies: &[PathBuf]) -> io::Result<()> {
+        let mut writer = Writer { bytes: vec![] };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u32(VERSION);
+
+        writer.u32(dependencies.len() as u32);
+        for path in dependencies {
+            let dependency = Dependency::new(path)?;
+            writer.path(&dependency.path);
+            writer.u64(dependency.len);
+            writer.u64(dependency.modified);
+            writer.u64(dependency.hash);
+        }
+
+        writer.u64(model.vertices.len() as u64);
+        for &attribute in model.vertices.iter().flatten() {
+            writer.f32(attribute);
+        }
+
+        writer.u32(model.indices.size_of_index() as u32);
+        writer.u64(model.indices.len() as u64);
+        match &model.indices {
+            Indices::U16(indices) => {
+                for index in indices {
+                    writer.bytes.extend_from_slice(&index.to_le_bytes());
+                }
+            }
+            Indices::U32(indices) => {
+                for &index in indices {
+                    writer.u32(index);
+                }
+            }
+        }
+
+        writer.u32(model.submeshes.len() as u32);
+        for submesh in &model.submeshes {
+            writer.string(&submesh.name);
+            writer.u64(submesh.indices.start as u64);
+            writer.u64(submesh.indices.end as u64);
+            writer.ranges(&submesh.ranges);
+            writer.ranges(&submesh.lines);
+            writer.ranges(&submesh.points);
+            writer.u32(submesh.visible as u32);
+        }
+
+        writer.u32(model.materials.len() as u32);
+        for material in model.materials.iter().map(|material| &material.desc) {
+            for value in [material.ambient, material.diffuse, material.specular].as_flattened() {
+                writer.f32(*value);
+            }
+            writer.f32(material.shininess);
+            for &value in &material.emissive {
+                writer.f32(value);
+            }
+            writer.f32(material.opacity);
+            writer.f32(material.optical_density);
+            writer.u32(material.illumination as u32);
+            writer.u32(material.pbr as u32);
+            for value in [
+                material.roughness,
+                material.metallic,
+                material.sheen,
+                material.clearcoat,
+                material.clearcoat_roughness,
+                material.anisotropy,
+            ] {
+                writer.f32(value);
+            }
+            writer.u32(material.bump_mode as u32);
+            for file in material.map_files.iter().chain(&material.cube_files) {
+                writer.map_file(file.as_deref());
+            }
+            for options in &material.map_options {
+                writer.map_options(options);
+            }
+        }
+
+        // Write to a temporary file first, so an interrupted write never leaves a torn cache
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, &writer.bytes)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+/// Whether every index points at a vertex, and every range at indices and a material that exist
+fn is_consistent(model: &Model) -> bool {
+    let index_count = model.indices.len();
+    let vertex_count = model.vertices.len();
+    let within = |range: &Range<usize>| range.start <= range.end && range.end <= index_count;
+    (0..index_count).all(|index| model.indices.get(index) < vertex_count)
+        && model.submeshes.iter().all(|submesh| {
+            within(&submesh.indices)
+                && [&submesh.ranges, &submesh.lines, &submesh.points]
+                    .into_iter()
+                    .flatten()
+                    .all(|range| within(&range.indices) && range.material < model.materials.len())
+        })
+}
+
+/// Little-endian values appended to a buffer
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    /// Paths that aren't valid UTF-8 are stored lossily, which just makes the cache miss later
+    fn path(&mut self, path: &Path) {
+        self.string(&path.to_string_lossy());
+    }
+
+    fn map_file(&mut self, file: Option<&Path>) {
+        match file {
+            Some(file) => {
+                self.u32(1);
+                self.path(file);
+            }
+            None => self.u32(0),
+        }
+    }
+
+    fn map_options(&mut self, options: &MapOptions) {
+        for value in options.scale.iter().chain(&options.offset) {
+            self.f32(*value);
+        }
+        self.u32(options.clamp as u32);
+        self.f32(options.bump_multiplier);
+        self.u32(options.channel.map_or(0, |channel| channel.letter() as u32));
+        self.f32(options.base_gain[0]);
+        self.f32(options.base_gain[1]);
+        self.u32(
+            options
+                .reflection_type
+                .map_or(0, |reflection_type| reflection_type as u32),
+        );
+    }
+
+    fn ranges(&mut self, ranges: &[MaterialRange]) {
+        self.u32(ranges.len() as u32);
+        for range in ranges {
+            self.u64(range.material as u64);
+            self.u64(range.indices.start as u64);
+            self.u64(range.indices.end as u64);
+        }
+    }
+}
+
+/// Reads values back in the order `Writer` wrote them. Every read returns `None` if the file
+/// ends early
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if count > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn f32x3(&mut self) -> Option<[f32; 3]> {
+        Some([self.f32()?, self.f32()?, self.f32()?])
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn path(&mut self) -> Option<PathBuf> {
+        self.string().map(PathBuf::from)
+    }
+
+    /// Reads `N` values one after another
+    fn array<T, const N: usize>(&mut self, read: fn(&mut Self) -> Option<T>) -> Option<[T; N]> {
+        let values: Vec<T> = (0..N).map(|_| read(self)).collect::<Option<_>>()?;
+        values.try_into().ok()
+    }
+
+    fn map_file(&mut self) -> Option<Option<PathBuf>> {
+        match self.u32()? {
+            0 => Some(None),
+            _ => self.path().map(Some),
+        }
+    }
+
+    fn map_options(&mut self) -> Option<MapOptions> {
+        Some(MapOptions {
+            scale: self.f32x3()?,
+            offset: self.f32x3()?,
+            clamp: self.u32()? != 0,
+            bump_multiplier: self.f32()?,
+            channel: match self.u32()? {
+                0 => None,
+                letter => Some(Channel::from_letter(char::from_u32(letter)?)?),
+            },
+            base_gain: [self.f32()?, self.f32()?],
+            reflection_type: match self.u32()? {
+                0 => None,
+                index => Some(ReflectionType::from_index(index)?),
+            },
+        })
+    }
+
+    fn ranges(&mut self) -> Option<Vec<MaterialRange>> {
+        (0..self.u32()?)
+            .map(|_| {
+                Some(MaterialRange {
+                    material: self.u64()? as usize,
+                    indices: self.u64()? as usize..self.u64()? as usize,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use crate::vertex_gen::{Index, VertexNormal, VertexPos};
+
+/// Normalizes a vector, leaving zero-length vectors as they are. Short vectors from tiny faces are
+/// still normalized, since their length only reflects the model's units
+fn normalize_or_zero(v: glm::Vec3) -> VertexNormal {
+    let length = glm::length(&v);
+    if length == 0.0 {
+        return [0.0, 0.0, 0.0];
+    }
+    let v = v / length;
+    [v.x, v.y, v.z]
+}
+
+/// Generates a normal for every triangle corner in `corners`.
+///
+/// `smoothing_groups` holds the `s` group of each triangle, where 0 means smoothing is off. Corners
+/// in the same nonzero group that share a position get a smooth normal, weighted by both the area
+/// of each face and its angle at that corner. Faces with smoothing off get flat normals. When
+/// `has_smoothing_groups` is false the file never used `s`, so `crease_angle` (in radians) decides
+/// instead: faces meeting at less than that angle are smoothed together
+pub fn generate_normals(
+    positions: &[VertexPos],
+    corners: &[Index],
+    smoothing_groups: &[u32],
+    has_smoothing_groups: bool,
+    crease_angle: Option<f32>,
+) -> Vec<VertexNormal> {
+    // The cross product's length is twice the triangle's area, so it gives the area weighting
+    let mut face_normals = Vec::with_capacity(corners.len() / 3);
+    let mut weighted = Vec::with_capacity(corners.len());
+    for triangle in corners.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|corner| {
+            let p = positions[triangle[corner][0] as usize - 1];
+            glm::vec3(p[0], p[1], p[2])
+        });
+        let face_normal = glm::cross(&(b - a), &(c - a));
+        face_normals.push(face_normal);
+        for (corner, prev, next) in [(a, c, b), (b, a, c), (c, b, a)] {
+            let angle = glm::angle(&(prev - corner), &(next - corner));
+            weighted.push(if angle.is_finite() {
+                face_normal * angle
+            } else {
+                glm::vec3(0.0, 0.0, 0.0)
+            });
+        }
+    }
+
+    if has_smoothing_groups {
+        let mut sums: HashMap<(u32, u32), glm::Vec3> = HashMap::new();
+        for (i, corner) in corners.iter().enumerate() {
+            let group = smoothing_groups[i / 3];
+            if group != 0 {
+                *sums
+                    .entry((corner[0], group))
+                    .or_insert(glm::vec3(0.0, 0.0, 0.0)) += weighted[i];
+            }
+        }
+        corners
+            .iter()
+            .enumerate()
+            .map(|(i, corner)| match smoothing_groups[i / 3] {
+                0 => normalize_or_zero(face_normals[i / 3]),
+                group => normalize_or_zero(sums[&(corner[0], group)]),
+            })
+            .collect()
+    } else if let Some(crease_angle) = crease_angle {
+        let unit_normals: Vec<VertexNormal> = face_normals
+            .iter()
+            .map(|&normal| normalize_or_zero(normal))
+            .collect();
+        let mut adjacent: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, corner) in corners.iter().enumerate() {
+            adjacent.entry(corner[0]).or_default().push(i);
+        }
+        let threshold = crease_angle.cos();
+        corners
+            .iter()
+            .enumerate()
+            .map(|(i, corner)| {
+                let own = glm::Vec3::from(unit_normals[i / 3]);
+                let sum = adjacent[&corner[0]]
+                    .iter()
+                    .filter(|&&other| {
+                        glm::dot(&own, &glm::Vec3::from(unit_normals[other / 3])) >= threshold
+                    })
+                    .fold(glm::vec3(0.0, 0.0, 0.0), |sum, &other| {
+                        sum + weighted[other]
+                    });
+                normalize_or_zero(sum)
+            })
+            .collect()
+    } else {
+        (0..corners.len())
+            .map(|i| normalize_or_zero(face_normals[i / 3]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_faces_get_unit_normals() {
+        let positions = [[0.0, 0.0, 0.0], [1e-4, 0.0, 0.0], [0.0, 1e-4, 0.0]];
+        let corners = [[1, 0, 0], [2, 0, 0], [3, 0, 0]];
+        let normals = generate_normals(&positions, &corners, &[0], false, None);
+        assert_eq!(normals, vec![[0.0, 0.0, 1.0]; 3]);
+    }
+}
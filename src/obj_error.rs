@@ -0,0 +1,164 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// The reason an OBJ or MTL file failed to load
+#[derive(Debug)]
+pub enum ObjErrorKind {
+    /// The file couldn't be opened or read
+    Io(std::io::Error),
+    /// A referenced image couldn't be opened or decoded
+    Image(Box<image::ImageError>),
+    /// A statement ended before all of its required values were given
+    MissingValue,
+    /// A value that should be a number couldn't be parsed as one
+    InvalidFloat,
+    /// A face index couldn't be parsed or was zero
+    InvalidIndex,
+    /// A face index refers to an element that doesn't exist
+    IndexOutOfRange,
+    /// A face has fewer than three vertices
+    DegenerateFace,
+    /// A line has fewer than two vertices
+    DegenerateLine,
+    /// A statement has a number of values that doesn't match any of its forms
+    UnexpectedValueCount,
+    /// A texture map option has a value it doesn't accept
+    InvalidOption,
+    /// A free-form type or direction that can't be tessellated
+    UnsupportedFreeForm,
+    /// A free-form curve or surface whose knots don't fit its degree and control points
+    InvalidKnots,
+    /// A free-form curve or surface is missing its `end` statement
+    UnterminatedFreeForm,
+}
+
+/// An error produced while loading an OBJ or MTL file, with enough context to point the user at
+/// the offending text
+#[derive(Debug)]
+pub struct ObjError {
+    pub path: PathBuf,
+    /// 1-based line number, or `None` if the error isn't tied to a particular line
+    pub line: Option<usize>,
+    /// The token that caused the error, or an empty string if there isn't one
+    pub token: String,
+    pub kind: ObjErrorKind,
+}
+
+impl ObjError {
+    pub fn new(path: &Path, line: Option<usize>, token: &str, kind: ObjErrorKind) -> Self {
+        ObjError {
+            path: path.to_path_buf(),
+            line,
+            token: token.to_string(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        write!(f, ": ")?;
+        match &self.kind {
+            ObjErrorKind::Io(err) => write!(f, "couldn't read file: {err}")?,
+            ObjErrorKind::Image(err) => write!(f, "couldn't load image: {err}")?,
+            ObjErrorKind::MissingValue => write!(f, "statement is missing a value")?,
+            ObjErrorKind::InvalidFloat => write!(f, "expected a number")?,
+            ObjErrorKind::InvalidIndex => write!(f, "invalid index")?,
+            ObjErrorKind::IndexOutOfRange => write!(f, "index out of range")?,
+            ObjErrorKind::DegenerateFace => write!(f, "face has fewer than three vertices")?,
+            ObjErrorKind::DegenerateLine => write!(f, "line has fewer than two vertices")?,
+            ObjErrorKind::UnexpectedValueCount => write!(f, "unexpected number of values")?,
+            ObjErrorKind::InvalidOption => write!(f, "invalid texture option")?,
+            ObjErrorKind::UnsupportedFreeForm => write!(f, "unsupported free-form geometry")?,
+            ObjErrorKind::InvalidKnots => {
+                write!(f, "knots don't match the degree and control points")?
+            }
+            ObjErrorKind::UnterminatedFreeForm => {
+                write!(f, "free-form geometry has no end statement")?
+            }
+        }
+        if !self.token.is_empty() {
+            write!(f, " (at \"{}\")", self.token)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ObjErrorKind::Io(err) => Some(err),
+            ObjErrorKind::Image(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Gets the next whitespace-separated value of a statement
+pub fn next_token<'a>(
+    split: &mut impl Iterator<Item = &'a str>,
+    path: &Path,
+    line: usize,
+    statement: &str,
+) -> Result<&'a str, ObjError> {
+    split
+        .next()
+        .ok_or_else(|| ObjError::new(path, Some(line), statement, ObjErrorKind::MissingValue))
+}
+
+/// Gets everything after the statement keyword of a line, keeping any spaces inside it, for
+/// values such as file names that may contain spaces
+pub fn rest_of_line<'a>(
+    line: &'a str,
+    path: &Path,
+    line_number: usize,
+    statement: &str,
+) -> Result<&'a str, ObjError> {
+    let rest = line
+        .trim_start()
+        .strip_prefix(statement)
+        .unwrap_or_default()
+        .trim();
+    if rest.is_empty() {
+        return Err(ObjError::new(
+            path,
+            Some(line_number),
+            statement,
+            ObjErrorKind::MissingValue,
+        ));
+    }
+    Ok(rest)
+}
+
+/// Parses the next value of a statement as a float
+pub fn parse_float<'a>(
+    split: &mut impl Iterator<Item = &'a str>,
+    path: &Path,
+    line: usize,
+    statement: &str,
+) -> Result<f32, ObjError> {
+    let token = next_token(split, path, line, statement)?;
+    token
+        .parse::<f32>()
+        .map_err(|_| ObjError::new(path, Some(line), token, ObjErrorKind::InvalidFloat))
+}
+
+/// Parses the next three values of a statement as floats
+pub fn parse_float3<'a>(
+    split: &mut impl Iterator<Item = &'a str>,
+    path: &Path,
+    line: usize,
+    statement: &str,
+) -> Result<[f32; 3], ObjError> {
+    Ok([
+        parse_float(split, path, line, statement)?,
+        parse_float(split, path, line, statement)?,
+        parse_float(split, path, line, statement)?,
+    ])
+}
@@ -0,0 +1,549 @@
+use memmap2::Mmap;
+use std::{fs::File, io, path::Path, thread, time::Instant};
+
+use crate::freeform::Basis;
+use crate::obj_error::{
+    next_token, parse_float, parse_float3, rest_of_line, ObjError, ObjErrorKind,
+};
+use crate::vertex_gen::{VertexColor, VertexNormal, VertexPos, VertexTex};
+
+/// Chunks smaller than this aren't worth handing to another thread
+const MIN_CHUNK_SIZE: usize = 1 << 20;
+
+/// One `v/vt/vn` reference of a face, line or point, before relative indices are resolved
+#[derive(Debug, PartialEq)]
+pub struct Corner<'a> {
+    /// The text of the reference, for error messages
+    pub token: &'a str,
+    /// Indices as written in the file, which may be negative. 0 means the component was omitted
+    pub index: [i64; 3],
+}
+
+/// A single OBJ statement, parsed without needing anything from the lines before it
+#[derive(Debug, PartialEq)]
+pub enum Statement<'a> {
+    /// Everything after `mtllib`, which may be several libraries or one name with spaces
+    Mtllib(&'a str),
+    Usemtl(&'a str),
+    /// Everything after `o` or `g`
+    Group(&'a str),
+    /// A smoothing group, where 0 means smoothing is off
+    Smooth(u32),
+    /// A position, its weight (1 unless given) and an optional color
+    Position(VertexPos, f32, Option<VertexColor>),
+    Uv(VertexTex),
+    Normal(VertexNormal),
+    Face(Vec<Corner<'a>>),
+    Line(Vec<Corner<'a>>),
+    Point(Vec<Corner<'a>>),
+    /// The basis of the free-form geometry that follows, or the name of one that can't be
+    /// tessellated, and whether it's rational
+    Cstype(Result<Basis, &'a str>, bool),
+    /// The free-form degree in u and v, where v is 0 if only one was given
+    Degree([usize; 2]),
+    /// A curve's parameter range and control points
+    Curve([f32; 2], Vec<Corner<'a>>),
+    /// A surface's parameter ranges in u and v, and its control points
+    Surface([[f32; 2]; 2], Vec<Corner<'a>>),
+    /// The parameter values for direction 0 (u) or 1 (v) of the current curve or surface, or the
+    /// name of a direction that doesn't exist
+    Parm(Result<usize, &'a str>, Vec<f32>),
+    /// Ends the current curve or surface
+    End,
+}
+
+/// The statements of a run of whole lines. If the run contains an error, parsing stopped there and
+/// `statements` holds everything before it
+pub struct Chunk<'a> {
+    /// Each statement with its 1-based line number in the whole file
+    pub statements: Vec<(usize, Statement<'a>)>,
+    pub error: Option<ObjError>,
+}
+
+/// The contents of an OBJ file, memory-mapped so large files aren't copied before parsing
+pub struct Source {
+    // Zero-length files can't be mapped
+    map: Option<Mmap>,
+}
+
+impl Source {
+    pub fn open(filepath: &Path) -> Result<Self, ObjError> {
+        let io_error = |err| ObjError::new(filepath, None, "", ObjErrorKind::Io(err));
+        let file = File::open(filepath).map_err(io_error)?;
+        let len = file.metadata().map_err(io_error)?.len();
+        let map = if len == 0 {
+            None
+        } else {
+            // SAFETY: The map is only read, and like any mapped file it's up to the user not to
+            // truncate the file while it's being loaded
+            Some(unsafe { Mmap::map(&file) }.map_err(io_error)?)
+        };
+        Ok(Source { map })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or_default()
+    }
+}
+
+/// Splits `bytes` into about `count` pieces that each end just after a newline that isn't
+/// escaped by a line continuation
+fn split_lines(bytes: &[u8], count: usize) -> Vec<&[u8]> {
+    let mut pieces = Vec::with_capacity(count);
+    let target = bytes.len().div_ceil(count.max(1));
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let mut end = target;
+        loop {
+            end = match rest.get(end..) {
+                Some(after) => after
+                    .iter()
+                    .position(|&byte| byte == b'\n')
+                    .map_or(rest.len(), |newline| end + newline + 1),
+                None => rest.len(),
+            };
+            if end == rest.len() || !rest[..end].trim_ascii_end().ends_with(b"\\") {
+                break;
+            }
+        }
+        let (piece, remaining) = rest.split_at(end);
+        pieces.push(piece);
+        rest = remaining;
+    }
+    pieces
+}
+
+/// Parses the statements of an OBJ file. With `parallel` set, large files are split into
+/// line-aligned chunks that are parsed on separate threads. The chunks come back in file order
+/// and hold exactly what a single sequential pass would have produced
+pub fn parse_statements<'a>(bytes: &'a [u8], filepath: &Path, parallel: bool) -> Vec<Chunk<'a>> {
+    let threads = if parallel {
+        let available = thread::available_parallelism().map_or(1, |threads| threads.get());
+        available.min(bytes.len() / MIN_CHUNK_SIZE).max(1)
+    } else {
+        1
+    };
+    parse_in_chunks(bytes, filepath, threads)
+}
+
+/// Parses the statements of an OBJ file split into about `count` chunks, each on its own thread
+fn parse_in_chunks<'a>(bytes: &'a [u8], filepath: &Path, count: usize) -> Vec<Chunk<'a>> {
+    let pieces = split_lines(bytes, count);
+
+    let mut chunks: Vec<(Chunk, usize)> = if pieces.len() > 1 {
+        thread::scope(|scope| {
+            let handles: Vec<_> = pieces
+                .iter()
+                .map(|&piece| scope.spawn(move || parse_chunk(piece, filepath)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("OBJ parser thread panicked"))
+                .collect()
+        })
+    } else {
+        pieces
+            .iter()
+            .map(|piece| parse_chunk(piece, filepath))
+            .collect()
+    };
+
+    // Each chunk numbered its lines from 1, so shift them to where the chunk starts in the file
+    let mut first_line = 0;
+    for (chunk, line_count) in &mut chunks {
+        for (line_number, _) in &mut chunk.statements {
+            *line_number += first_line;
+        }
+        if let Some(error) = &mut chunk.error {
+            error.line = error.line.map(|line_number| line_number + first_line);
+        }
+        first_line += *line_count;
+    }
+    chunks.into_iter().map(|(chunk, _)| chunk).collect()
+}
+
+/// Parses a run of whole lines, numbering them from 1. Also returns how many lines there were
+fn parse_chunk<'a>(bytes: &'a [u8], filepath: &Path) -> (Chunk<'a>, usize) {
+    let mut chunk = Chunk {
+        statements: vec![],
+        error: None,
+    };
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(err) => {
+            let err = io::Error::new(io::ErrorKind::InvalidData, err);
+            chunk.error = Some(ObjError::new(filepath, None, "", ObjErrorKind::Io(err)));
+            return (chunk, 0);
+        }
+    };
+
+    let mut line_count = 0;
+    let mut lines = text.lines();
+    let mut continued = vec![];
+    while let Some(line) = lines.next() {
+        line_count += 1;
+        let line_number = line_count;
+        let line = line.trim();
+        // A trailing backslash continues the statement on the next line, which long free-form
+        // control point lists often use
+        let parts: &[&str] = if line.ends_with('\\') {
+            continued.clear();
+            let mut part = line;
+            while let Some(before) = part.strip_suffix('\\') {
+                continued.push(before);
+                part = match lines.next() {
+                    Some(next) => {
+                        line_count += 1;
+                        next.trim()
+                    }
+                    None => "",
+                };
+            }
+            continued.push(part);
+            &continued
+        } else {
+            std::slice::from_ref(&line)
+        };
+        match parse_line(parts, filepath, line_number) {
+            Ok(Some(statement)) => chunk.statements.push((line_number, statement)),
+            Ok(None) => {}
+            Err(err) => {
+                chunk.error = Some(err);
+                break;
+            }
+        }
+    }
+    // The line count only matters for chunks after this one, which only exist if this chunk ran
+    // to the end, so stopping early at an error is fine
+    (chunk, line_count)
+}
+
+fn parse_corners<'a>(
+    split: impl Iterator<Item = &'a str>,
+    filepath: &Path,
+    line_number: usize,
+) -> Result<Vec<Corner<'a>>, ObjError> {
+    split
+        .map(|token| {
+            parse_corner(token)
+                .map(|index| Corner { token, index })
+                .ok_or_else(|| {
+                    ObjError::new(
+                        filepath,
+                        Some(line_number),
+                        token,
+                        ObjErrorKind::InvalidIndex,
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Parses one `v`, `v/vt`, `v//vn` or `v/vt/vn` reference, leaving omitted components as 0
+fn parse_corner(token: &str) -> Option<[i64; 3]> {
+    let mut index = [0, 0, 0];
+    for (i, component) in token.split('/').enumerate() {
+        if i >= 3 {
+            return None;
+        }
+        if component.is_empty() && i > 0 {
+            continue;
+        }
+        index[i] = match component.parse::<i64>().ok()? {
+            0 => return None,
+            x => x,
+        };
+    }
+    Some(index)
+}
+
+/// Parses one statement, given as the lines it was continued across
+fn parse_line<'a>(
+    parts: &[&'a str],
+    filepath: &Path,
+    line_number: usize,
+) -> Result<Option<Statement<'a>>, ObjError> {
+    let line = parts[0];
+    let mut split = parts.iter().flat_map(|&part| part.split_whitespace());
+    let statement = match split.next() {
+        Some(statement @ "mtllib") => {
+            Statement::Mtllib(rest_of_line(line, filepath, line_number, statement)?)
+        }
+        Some(statement @ "usemtl") => {
+            Statement::Usemtl(rest_of_line(line, filepath, line_number, statement)?)
+        }
+        Some(statement @ ("o" | "g")) => {
+            Statement::Group(line.strip_prefix(statement).unwrap_or_default())
+        }
+        Some(statement @ "s") => {
+            let group = next_token(&mut split, filepath, line_number, statement)?;
+            Statement::Smooth(match group {
+                "off" => 0,
+                group => group.parse::<u32>().map_err(|_| {
+                    ObjError::new(
+                        filepath,
+                        Some(line_number),
+                        group,
+                        ObjErrorKind::InvalidIndex,
+                    )
+                })?,
+            })
+        }
+        Some(statement @ "v") => {
+            let position = parse_float3(&mut split, filepath, line_number, statement)?;
+            // An optional fourth value is the weight, while three extra values are an RGB
+            // color as written by MeshLab, ZBrush and photogrammetry tools. Both together put
+            // the weight first
+            let extra: Vec<&str> = split.collect();
+            let mut values = extra.iter().copied();
+            let mut weight = 1.0;
+            let mut color = None;
+            match extra.len() {
+                0 => {}
+                1 => weight = parse_float(&mut values, filepath, line_number, statement)?,
+                3 => color = Some(parse_float3(&mut values, filepath, line_number, statement)?),
+                4 => {
+                    weight = parse_float(&mut values, filepath, line_number, statement)?;
+                    color = Some(parse_float3(&mut values, filepath, line_number, statement)?);
+                }
+                _ => {
+                    return Err(ObjError::new(
+                        filepath,
+                        Some(line_number),
+                        &extra.join(" "),
+                        ObjErrorKind::UnexpectedValueCount,
+                    ))
+                }
+            }
+            Statement::Position(position, weight, color)
+        }
+        Some(statement @ "vt") => Statement::Uv([
+            parse_float(&mut split, filepath, line_number, statement)?,
+            parse_float(&mut split, filepath, line_number, statement)?,
+        ]),
+        Some(statement @ "vn") => {
+            Statement::Normal(parse_float3(&mut split, filepath, line_number, statement)?)
+        }
+        Some("f") => {
+            let corners = parse_corners(split, filepath, line_number)?;
+            if corners.len() < 3 {
+                return Err(ObjError::new(
+                    filepath,
+                    Some(line_number),
+                    line,
+                    ObjErrorKind::DegenerateFace,
+                ));
+            }
+            Statement::Face(corners)
+        }
+        Some("l") => {
+            let corners = parse_corners(split, filepath, line_number)?;
+            if corners.len() < 2 {
+                return Err(ObjError::new(
+                    filepath,
+                    Some(line_number),
+                    line,
+                    ObjErrorKind::DegenerateLine,
+                ));
+            }
+            Statement::Line(corners)
+        }
+        Some(statement @ "p") => {
+            let corners = parse_corners(split, filepath, line_number)?;
+            if corners.is_empty() {
+                return Err(ObjError::new(
+                    filepath,
+                    Some(line_number),
+                    statement,
+                    ObjErrorKind::MissingValue,
+                ));
+            }
+            Statement::Point(corners)
+        }
+        Some(statement @ "cstype") => {
+            let mut kind = next_token(&mut split, filepath, line_number, statement)?;
+            let rational = kind == "rat";
+            if rational {
+                kind = next_token(&mut split, filepath, line_number, statement)?;
+            }
+            // Other bases are skipped when a curve or surface uses them, rather than failing the
+            // polygons around them
+            let basis = match kind {
+                "bezier" => Ok(Basis::Bezier),
+                "bspline" => Ok(Basis::BSpline),
+                kind => Err(kind),
+            };
+            Statement::Cstype(basis, rational)
+        }
+        Some(statement @ "deg") => {
+            let mut degree = [0, 0];
+            for (i, token) in split.take(2).enumerate() {
+                degree[i] = token.parse::<usize>().map_err(|_| {
+                    ObjError::new(
+                        filepath,
+                        Some(line_number),
+                        token,
+                        ObjErrorKind::InvalidFloat,
+                    )
+                })?;
+            }
+            if degree[0] == 0 {
+                return Err(ObjError::new(
+                    filepath,
+                    Some(line_number),
+                    statement,
+                    ObjErrorKind::MissingValue,
+                ));
+            }
+            Statement::Degree(degree)
+        }
+        Some(statement @ "curv") => {
+            let range = [
+                parse_float(&mut split, filepath, line_number, statement)?,
+                parse_float(&mut split, filepath, line_number, statement)?,
+            ];
+            Statement::Curve(range, parse_corners(split, filepath, line_number)?)
+        }
+        Some(statement @ "surf") => {
+            let mut ranges = [[0.0; 2]; 2];
+            for value in ranges.as_flattened_mut() {
+                *value = parse_float(&mut split, filepath, line_number, statement)?;
+            }
+            Statement::Surface(ranges, parse_corners(split, filepath, line_number)?)
+        }
+        Some(statement @ "parm") => {
+            let axis = match next_token(&mut split, filepath, line_number, statement)? {
+                "u" => Ok(0),
+                "v" => Ok(1),
+                direction => Err(direction),
+            };
+            let values = split
+                .map(|token| {
+                    token.parse::<f32>().map_err(|_| {
+                        ObjError::new(
+                            filepath,
+                            Some(line_number),
+                            token,
+                            ObjErrorKind::InvalidFloat,
+                        )
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            Statement::Parm(axis, values)
+        }
+        Some("end") => Statement::End,
+        _ => return Ok(None),
+    };
+    Ok(Some(statement))
+}
+
+/// Joins chunks back into one list of statements, ending at the first error
+fn flatten(chunks: Vec<Chunk<'_>>) -> (Vec<(usize, Statement<'_>)>, Option<String>) {
+    let mut statements = vec![];
+    for chunk in chunks {
+        statements.extend(chunk.statements);
+        if let Some(err) = chunk.error {
+            return (statements, Some(err.to_string()));
+        }
+    }
+    (statements, None)
+}
+
+/// Times sequential against parallel parsing of an OBJ file and checks that both give the same
+/// statements. Run with `--bench <file.obj>`
+pub fn bench(filepath: &Path) {
+    let source = match Source::open(filepath) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    let bytes = source.bytes();
+
+    // Take the best of several runs so the page cache and thread startup don't skew the result
+    let time = |parallel| {
+        (0..5)
+            .map(|_| {
+                let start = Instant::now();
+                std::hint::black_box(parse_statements(bytes, filepath, parallel));
+                start.elapsed()
+            })
+            .min()
+            .unwrap_or_default()
+    };
+    let sequential_time = time(false);
+    let parallel_time = time(true);
+
+    let identical = flatten(parse_statements(bytes, filepath, false))
+        == flatten(parse_statements(bytes, filepath, true));
+
+    println!(
+        "{}: {:.1} MiB",
+        filepath.display(),
+        bytes.len() as f64 / (1 << 20) as f64
+    );
+    println!("  sequential: {sequential_time:?}");
+    println!(
+        "  parallel:   {parallel_time:?} ({:.2}x)",
+        sequential_time.as_secs_f64() / parallel_time.as_secs_f64()
+    );
+    println!("  identical output: {identical}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file with comments, blank lines, CRLF endings and statements continued across lines, so
+    /// that chunk boundaries land on all of them
+    fn sample(error_at: Option<usize>) -> String {
+        let mut obj = String::new();
+        for i in 0..400 {
+            obj += &format!("v {i} {} 1\n", i as f32 * 0.5);
+            match i % 6 {
+                0 => obj += "# a comment\n\n",
+                1 => obj += "vt 0.5 0.25\r\n",
+                2 => obj += "f 1 2 \\\n  3 \\\n  4\n",
+                3 => obj += &format!("g part {i}\n"),
+                4 => obj += "l 1 2 3\n",
+                _ => obj += "usemtl a material\n",
+            }
+            if error_at == Some(i) {
+                obj += "f 1 x 3\n";
+            }
+        }
+        obj
+    }
+
+    #[test]
+    fn chunks_split_between_statements() {
+        let obj = sample(None);
+        for count in [2, 3, 7, 16] {
+            let pieces = split_lines(obj.as_bytes(), count);
+            assert!(pieces.len() > 1);
+            assert_eq!(pieces.concat(), obj.as_bytes());
+            for piece in &pieces[..pieces.len() - 1] {
+                assert!(piece.ends_with(b"\n"));
+                assert!(!piece.trim_ascii_end().ends_with(b"\\"));
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_parse_matches_sequential() {
+        let path = Path::new("sample.obj");
+        for error_at in [None, Some(0), Some(150), Some(399)] {
+            let obj = sample(error_at);
+            let sequential = flatten(parse_in_chunks(obj.as_bytes(), path, 1));
+            assert_eq!(sequential.1.is_some(), error_at.is_some());
+            for count in [2, 3, 7, 16] {
+                let chunked = flatten(parse_in_chunks(obj.as_bytes(), path, count));
+                assert!(
+                    chunked == sequential,
+                    "{count} chunks, error at {error_at:?}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use gl33::global_loader::*;
+use gl33::*;
+use std::fs;
+
+/// Compiles one stage from a GLSL file, panicking with the info log if it fails
+unsafe fn compile_shader(kind: ShaderType, path: &str) -> u32 {
+    let shader = glCreateShader(kind);
+    assert_ne!(shader, 0, "Shader {path} was null");
+
+    let source =
+        fs::read_to_string(path).unwrap_or_else(|err| panic!("Couldn't open {path}: {err}"));
+    glShaderSource(
+        shader,
+        1,
+        &(source.as_bytes().as_ptr().cast()),
+        &(source.len() as i32),
+    );
+    glCompileShader(shader);
+
+    let mut success = 0;
+    glGetShaderiv(shader, GL_COMPILE_STATUS, &mut success);
+    if success == 0 {
+        let mut v: Vec<u8> = Vec::with_capacity(1024);
+        let mut log_len = 0_i32;
+        glGetShaderInfoLog(shader, 1024, &mut log_len, v.as_mut_ptr().cast());
+        v.set_len(log_len.try_into().unwrap());
+        panic!("{path} Compile Error: {}", String::from_utf8_lossy(&v));
+    }
+    shader
+}
+
+/// Compiles and links a program from a vertex and a fragment shader file, panicking if either
+/// fails
+pub unsafe fn compile_program(vertex_path: &str, fragment_path: &str) -> u32 {
+    link_program(&[
+        compile_shader(GL_VERTEX_SHADER, vertex_path),
+        compile_shader(GL_FRAGMENT_SHADER, fragment_path),
+    ])
+}
+
+/// Like `compile_program`, with a geometry shader between the two stages
+pub unsafe fn compile_program_with_geometry(
+    vertex_path: &str,
+    geometry_path: &str,
+    fragment_path: &str,
+) -> u32 {
+    link_program(&[
+        compile_shader(GL_VERTEX_SHADER, vertex_path),
+        compile_shader(GL_GEOMETRY_SHADER, geometry_path),
+        compile_shader(GL_FRAGMENT_SHADER, fragment_path),
+    ])
+}
+
+/// Links compiled shaders into a program, panicking with the info log if it fails
+unsafe fn link_program(shaders: &[u32]) -> u32 {
+    let program = glCreateProgram();
+    for &shader in shaders {
+        glAttachShader(program, shader);
+    }
+    glLinkProgram(program);
+
+    let mut success = 0;
+    glGetProgramiv(program, GL_LINK_STATUS, &mut success);
+    if success == 0 {
+        let mut v: Vec<u8> = Vec::with_capacity(1024);
+        let mut log_len = 0_i32;
+        glGetProgramInfoLog(program, 1024, &mut log_len, v.as_mut_ptr().cast());
+        v.set_len(log_len.try_into().unwrap());
+        panic!("Program Link Error: {}", String::from_utf8_lossy(&v));
+    }
+
+    // The program keeps what it needs from the shaders
+    for &shader in shaders {
+        glDeleteShader(shader);
+    }
+    program
+}
@@ -0,0 +1,102 @@
+use crate::vertex_gen::Vertex;
+
+/// Any unit vector perpendicular to `normal`, or the x axis if the normal is zero
+fn perpendicular(normal: glm::Vec3) -> glm::Vec3 {
+    let axis = if normal.x.abs() < 0.9 {
+        glm::vec3(1.0, 0.0, 0.0)
+    } else {
+        glm::vec3(0.0, 1.0, 0.0)
+    };
+    let perpendicular = glm::cross(&normal, &axis);
+    if glm::length(&perpendicular) > f32::EPSILON {
+        glm::normalize(&perpendicular)
+    } else {
+        axis
+    }
+}
+
+/// Fills in the tangent and bitangent of every vertex used by `triangles`, which point along
+/// increasing u and v of the texture coordinates.
+///
+/// The directions from every triangle sharing a vertex are summed, then made perpendicular to the
+/// vertex normal. The bitangent is rebuilt from the normal and tangent and only keeps its sign, so
+/// mirrored texture coordinates still work. Vertices without usable texture coordinates get an
+/// arbitrary frame around the normal instead. Vertices only used by lines and points keep zeros
+pub fn generate_tangents(vertices: &mut [Vertex], triangles: &[u32]) {
+    let position = |vertex: &Vertex| glm::vec3(vertex[0], vertex[1], vertex[2]);
+    let uv = |vertex: &Vertex| glm::vec2(vertex[3], vertex[4]);
+
+    let zero = glm::vec3(0.0, 0.0, 0.0);
+    let mut sums = vec![None; vertices.len()];
+    for triangle in triangles.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|corner| &vertices[triangle[corner] as usize]);
+        let (edge1, edge2) = (position(b) - position(a), position(c) - position(a));
+        let (delta1, delta2) = (uv(b) - uv(a), uv(c) - uv(a));
+        let determinant = delta1.x * delta2.y - delta2.x * delta1.y;
+        // Small texture coordinate deltas on dense meshes still give a usable frame, as long as
+        // dividing by the determinant can't overflow
+        let (tangent, bitangent) = if determinant.is_normal() {
+            (
+                (edge1 * delta2.y - edge2 * delta1.y) / determinant,
+                (edge2 * delta1.x - edge1 * delta2.x) / determinant,
+            )
+        } else {
+            (zero, zero)
+        };
+        for &index in triangle {
+            let (tangent_sum, bitangent_sum) = sums[index as usize].get_or_insert((zero, zero));
+            *tangent_sum += tangent;
+            *bitangent_sum += bitangent;
+        }
+    }
+
+    for (vertex, sum) in vertices.iter_mut().zip(sums) {
+        let Some((tangent, bitangent)) = sum else {
+            continue;
+        };
+        let normal = glm::vec3(vertex[5], vertex[6], vertex[7]);
+        // Gram-Schmidt against the normal. Whatever survives it is only noise if it's tiny next to
+        // the sum it came from
+        let projected = tangent - normal * glm::dot(&normal, &tangent);
+        let tangent = if glm::length(&projected) > f32::EPSILON * glm::length(&tangent) {
+            glm::normalize(&projected)
+        } else {
+            perpendicular(normal)
+        };
+        let mut bitangent_dir = glm::cross(&normal, &tangent);
+        if glm::dot(&bitangent_dir, &bitangent) < 0.0 {
+            bitangent_dir = -bitangent_dir;
+        }
+        vertex[11..14].copy_from_slice(tangent.as_slice());
+        vertex[14..17].copy_from_slice(bitangent_dir.as_slice());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_uv_deltas_keep_their_direction() {
+        // u runs along y and v along x, the opposite of the frame `perpendicular` would pick
+        let scale = 1e-4;
+        let corners = [[0.0, 0.0], [scale, 0.0], [0.0, scale]];
+        let mut vertices: Vec<Vertex> = corners
+            .iter()
+            .map(|&[x, y]| {
+                let mut vertex = [0.0; 17];
+                vertex[..3].copy_from_slice(&[x, y, 0.0]);
+                vertex[3..5].copy_from_slice(&[y, x]);
+                vertex[5..8].copy_from_slice(&[0.0, 0.0, -1.0]);
+                vertex
+            })
+            .collect();
+        generate_tangents(&mut vertices, &[0, 1, 2]);
+        for vertex in vertices {
+            let tangent = glm::vec3(vertex[11], vertex[12], vertex[13]);
+            let bitangent = glm::vec3(vertex[14], vertex[15], vertex[16]);
+            assert!(glm::distance(&tangent, &glm::vec3(0.0, 1.0, 0.0)) < 1e-6);
+            assert!(glm::distance(&bitangent, &glm::vec3(1.0, 0.0, 0.0)) < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use gl33::global_loader::*;
+use gl33::*;
+use image::{ColorType, DynamicImage, ImageReader};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::{Rc, Weak},
+};
+
+use crate::obj_error::{ObjError, ObjErrorKind};
+
+/// A GL texture shared by every material that uses it. It's deleted once the last one is dropped,
+/// so the GL context has to outlive it
+#[derive(Debug)]
+pub struct Texture {
+    pub id: u32,
+    /// Whether every pixel of the image is a shade of gray. Always false for cube maps
+    pub grayscale: bool,
+    /// Whether the image has an alpha channel. Always false for cube maps
+    pub has_alpha: bool,
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe { glDeleteTextures(1, &self.id) };
+    }
+}
+
+/// What makes two textures interchangeable: the same pixels, sampled the same way
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum TextureKey {
+    /// A 2D image by canonical path, whether it's clamped rather than repeated and whether it's
+    /// decoded from sRGB
+    Image {
+        path: PathBuf,
+        clamp: bool,
+        srgb: bool,
+    },
+    /// A cube map by the canonical paths of its faces, and whether they're decoded from sRGB
+    Cube([Option<PathBuf>; 6], bool),
+    /// The 1x1 white texture that stands in for missing maps
+    White,
+}
+
+/// Textures that are already uploaded, so an image used by several maps, materials or models is
+/// only decoded and uploaded once. Entries don't keep their textures alive
+#[derive(Default)]
+pub struct TextureCache {
+    textures: RefCell<HashMap<TextureKey, Weak<Texture>>>,
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn decode_image(path: &Path) -> Result<DynamicImage, ObjError> {
+    ImageReader::open(path)
+        .map_err(ObjErrorKind::Io)
+        .and_then(|reader| {
+            reader
+                .decode()
+                .map_err(|err| ObjErrorKind::Image(Box::new(err)))
+        })
+        .map_err(|kind| ObjError::new(path, None, "", kind))
+}
+
+/// Whether every pixel of the image is a shade of gray, even if it's stored with color channels
+fn is_grayscale(img: &DynamicImage) -> bool {
+    !img.color().has_color()
+        || img
+            .to_rgb8()
+            .pixels()
+            .all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2])
+}
+
+/// The internal format, pixel format and type that upload an image of `color` without
+/// converting it, or `None` for layouts GL can't take as is. With `srgb` the texture is decoded
+/// to linear values when sampled, which GL only does for 8-bit channels
+fn gl_format(color: ColorType, srgb: bool) -> Option<(GLenum, GLenum, GLenum)> {
+    Some(match (color, srgb) {
+        (ColorType::L8, true) => (GL_SRGB8, GL_RED, GL_UNSIGNED_BYTE),
+        (ColorType::Rgb8, true) => (GL_SRGB8, GL_RGB, GL_UNSIGNED_BYTE),
+        (ColorType::Rgba8, true) => (GL_SRGB8_ALPHA8, GL_RGBA, GL_UNSIGNED_BYTE),
+        // Float images already hold linear values
+        (ColorType::Rgb32F | ColorType::Rgba32F, _) | (_, false) => return linear_format(color),
+        // Gray with alpha would decode its alpha from the green channel, and 16-bit images have
+        // no sRGB format, so both are converted
+        _ => return None,
+    })
+}
+
+fn linear_format(color: ColorType) -> Option<(GLenum, GLenum, GLenum)> {
+    Some(match color {
+        ColorType::L8 => (GL_R8, GL_RED, GL_UNSIGNED_BYTE),
+        ColorType::La8 => (GL_RG8, GL_RG, GL_UNSIGNED_BYTE),
+        ColorType::Rgb8 => (GL_RGB8, GL_RGB, GL_UNSIGNED_BYTE),
+        ColorType::Rgba8 => (GL_RGBA8, GL_RGBA, GL_UNSIGNED_BYTE),
+        ColorType::L16 => (GL_R16, GL_RED, GL_UNSIGNED_SHORT),
+        ColorType::La16 => (GL_RG16, GL_RG, GL_UNSIGNED_SHORT),
+        ColorType::Rgb16 => (GL_RGB16, GL_RGB, GL_UNSIGNED_SHORT),
+        ColorType::Rgba16 => (GL_RGBA16, GL_RGBA, GL_UNSIGNED_SHORT),
+        ColorType::Rgb32F => (GL_RGB32F, GL_RGB, GL_FLOAT),
+        ColorType::Rgba32F => (GL_RGBA32F, GL_RGBA, GL_FLOAT),
+        _ => return None,
+    })
+}
+
+/// Uploads an image to the bound texture's `target`, which is either the 2D texture or a face of
+/// a cube map. The image is stored in the format it was decoded in, and grayscale images are
+/// swizzled so they still read as gray in every channel
+unsafe fn upload_pixels(target: TextureTarget, img: &DynamicImage, srgb: bool) {
+    let converted;
+    let (img, (internal_format, format, kind)) = match gl_format(img.color(), srgb) {
+        Some(gl_format) => (img, gl_format),
+        None => {
+            converted = DynamicImage::from(img.to_rgba8());
+            let internal_format = if srgb { GL_SRGB8_ALPHA8 } else { GL_RGBA8 };
+            (&converted, (internal_format, GL_RGBA, GL_UNSIGNED_BYTE))
+        }
+    };
+
+    let swizzle = match img.color().channel_count() {
+        1 => [GL_RED, GL_RED, GL_RED, GL_ONE],
+        2 => [GL_RED, GL_RED, GL_RED, GL_GREEN],
+        _ => [GL_RED, GL_GREEN, GL_BLUE, GL_ALPHA],
+    }
+    .map(|channel| channel.0 as i32);
+    let texture_target = if target == GL_TEXTURE_2D {
+        GL_TEXTURE_2D
+    } else {
+        GL_TEXTURE_CUBE_MAP
+    };
+    glTexParameteriv(texture_target, GL_TEXTURE_SWIZZLE_RGBA, swizzle.as_ptr());
+
+    // Rows are tightly packed, so they're only as aligned as their length allows
+    let row_bytes = img.width() as usize * img.color().bytes_per_pixel() as usize;
+    let alignment = [8, 4, 2, 1]
+        .into_iter()
+        .find(|&alignment| row_bytes.is_multiple_of(alignment))
+        .unwrap_or(1);
+    glPixelStorei(GL_UNPACK_ALIGNMENT, alignment as i32);
+
+    glTexImage2D(
+        target,
+        0,
+        internal_format.0 as _,
+        img.width() as _,
+        img.height() as _,
+        0,
+        format,
+        kind,
+        img.as_bytes().as_ptr() as _,
+    );
+}
+
+/// Uploads an image as a mipmapped GL texture, repeating unless it's clamped
+fn upload_image(img: &DynamicImage, clamp: bool, srgb: bool) -> u32 {
+    let wrap = if clamp { GL_CLAMP_TO_BORDER } else { GL_REPEAT };
+
+    unsafe {
+        let mut texture: u32 = 0;
+        glGenTextures(1, &mut texture);
+        glBindTexture(GL_TEXTURE_2D, texture);
+        glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, wrap.0 as _);
+        glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, wrap.0 as _);
+        glTexParameteri(
+            GL_TEXTURE_2D,
+            GL_TEXTURE_MIN_FILTER,
+            GL_LINEAR_MIPMAP_LINEAR.0 as _,
+        );
+        glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_LINEAR.0 as _);
+        // GL's first row is the bottom of the image
+        upload_pixels(GL_TEXTURE_2D, &img.flipv(), srgb);
+        glGenerateMipmap(GL_TEXTURE_2D);
+        texture
+    }
+}
+
+/// Uploads the given faces as a cube map. Faces need to be square and all the same size, or the
+/// cube map samples black
+fn upload_cube_map(faces: &[(usize, DynamicImage)], srgb: bool) -> u32 {
+    unsafe {
+        let mut cube_map: u32 = 0;
+        glGenTextures(1, &mut cube_map);
+        glBindTexture(GL_TEXTURE_CUBE_MAP, cube_map);
+        glTexParameteri(GL_TEXTURE_CUBE_MAP, GL_TEXTURE_MIN_FILTER, GL_LINEAR.0 as _);
+        glTexParameteri(GL_TEXTURE_CUBE_MAP, GL_TEXTURE_MAG_FILTER, GL_LINEAR.0 as _);
+        for wrap in [GL_TEXTURE_WRAP_S, GL_TEXTURE_WRAP_T, GL_TEXTURE_WRAP_R] {
+            glTexParameteri(GL_TEXTURE_CUBE_MAP, wrap, GL_CLAMP_TO_EDGE.0 as _);
+        }
+        for (face, img) in faces {
+            // Cube map faces start at the top row, unlike 2D textures
+            let target = GLenum(GL_TEXTURE_CUBE_MAP_POSITIVE_X.0 + *face as u32);
+            upload_pixels(target, img, srgb);
+        }
+        cube_map
+    }
+}
+
+impl TextureCache {
+    /// Returns the texture for `key` if something still holds it, or makes it with `create`
+    fn get_or_insert(
+        &self,
+        key: TextureKey,
+        create: impl FnOnce() -> Result<Texture, ObjError>,
+    ) -> Result<Rc<Texture>, ObjError> {
+        if let Some(texture) = self.textures.borrow().get(&key).and_then(Weak::upgrade) {
+            return Ok(texture);
+        }
+        let texture = Rc::new(create()?);
+        let mut textures = self.textures.borrow_mut();
+        textures.retain(|_, texture| texture.strong_count() > 0);
+        textures.insert(key, Rc::downgrade(&texture));
+        Ok(texture)
+    }
+
+    /// Gets an image file as a mipmapped 2D texture, repeating unless it's clamped. Color images
+    /// should be `srgb`, while data such as normals or roughness is stored linearly
+    pub fn image(&self, path: &Path, clamp: bool, srgb: bool) -> Result<Rc<Texture>, ObjError> {
+        let key = TextureKey::Image {
+            path: canonical(path),
+            clamp,
+            srgb,
+        };
+        self.get_or_insert(key, || {
+            let img = decode_image(path)?;
+            Ok(Texture {
+                id: upload_image(&img, clamp, srgb),
+                grayscale: is_grayscale(&img),
+                has_alpha: img.color().has_alpha(),
+            })
+        })
+    }
+
+    /// Gets a cube map made from image files, in the order of `ReflectionType::cube_face`. Faces
+    /// without a file are left empty
+    pub fn cube_map(
+        &self,
+        files: &[Option<PathBuf>; 6],
+        srgb: bool,
+    ) -> Result<Rc<Texture>, ObjError> {
+        let files_key = files.clone().map(|file| file.as_deref().map(canonical));
+        let key = TextureKey::Cube(files_key, srgb);
+        self.get_or_insert(key, || {
+            let faces = files
+                .iter()
+                .enumerate()
+                .filter_map(|(face, file)| Some((face, file.as_deref()?)))
+                .map(|(face, file)| Ok((face, decode_image(file)?)))
+                .collect::<Result<Vec<_>, ObjError>>()?;
+            Ok(Texture {
+                id: upload_cube_map(&faces, srgb),
+                grayscale: false,
+                has_alpha: false,
+            })
+        })
+    }
+
+    /// Gets a 1x1 white texture, which leaves whatever it multiplies unchanged
+    pub fn white(&self) -> Rc<Texture> {
+        let result = self.get_or_insert(TextureKey::White, || unsafe {
+            let mut texture: u32 = 0;
+            glGenTextures(1, &mut texture);
+            glBindTexture(GL_TEXTURE_2D, texture);
+            glTexImage2D(
+                GL_TEXTURE_2D,
+                0,
+                GL_RGB.0 as _,
+                1,
+                1,
+                0,
+                GL_RGB,
+                GL_UNSIGNED_BYTE,
+                [255u8, 255u8, 255u8].as_ptr() as _,
+            );
+            Ok(Texture {
+                id: texture,
+                grayscale: true,
+                has_alpha: false,
+            })
+        });
+        result.expect("Creating the white texture can't fail")
+    }
+}
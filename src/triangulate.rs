@@ -0,0 +1,136 @@
+use crate::vertex_gen::VertexPos;
+
+/// Splits a polygon into triangles using ear clipping on the polygon's best-fit plane. Returns
+/// triangles as indices into `points`, wound the same way as the polygon itself
+pub fn triangulate(points: &[VertexPos]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n < 3 {
+        return vec![];
+    }
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    // Areas are measured against the square of the polygon's size, so that whether a polygon or
+    // corner counts as degenerate doesn't depend on the model's units
+    let mut min = points[0];
+    let mut max = points[0];
+    for point in points {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(point[axis]);
+            max[axis] = max[axis].max(point[axis]);
+        }
+    }
+    let diagonal = glm::vec3(max[0] - min[0], max[1] - min[1], max[2] - min[2]);
+    let tolerance = f32::EPSILON * glm::length2(&diagonal);
+
+    // Newell's method gives a normal that is robust to concave and slightly non-planar polygons
+    let mut normal = glm::vec3(0.0, 0.0, 0.0);
+    for i in 0..n {
+        let current = points[i];
+        let next = points[(i + 1) % n];
+        normal.x += (current[1] - next[1]) * (current[2] + next[2]);
+        normal.y += (current[2] - next[2]) * (current[0] + next[0]);
+        normal.z += (current[0] - next[0]) * (current[1] + next[1]);
+    }
+    if glm::length(&normal) <= tolerance {
+        // The polygon has no area, so any split is as good as another
+        return (1..n - 1).map(|i| [0, i, i + 1]).collect();
+    }
+    let normal = glm::normalize(&normal);
+
+    // Build a basis for the plane such that u x v = normal, which makes the projected polygon
+    // counter-clockwise
+    let axis = if normal.x.abs() < 0.9 {
+        glm::vec3(1.0, 0.0, 0.0)
+    } else {
+        glm::vec3(0.0, 1.0, 0.0)
+    };
+    let u = glm::normalize(&glm::cross(&axis, &normal));
+    let v = glm::cross(&normal, &u);
+    let projected: Vec<glm::Vec2> = points
+        .iter()
+        .map(|point| {
+            let point = glm::vec3(point[0], point[1], point[2]);
+            glm::vec2(glm::dot(&point, &u), glm::dot(&point, &v))
+        })
+        .collect();
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n - 2);
+    // Starting at the second vertex makes convex quads split the same way as a fan would
+    let mut i = 1;
+    let mut attempts = 0;
+    while remaining.len() > 3 {
+        let len = remaining.len();
+        i %= len;
+        let prev = remaining[(i + len - 1) % len];
+        let current = remaining[i];
+        let next = remaining[(i + 1) % len];
+
+        // If a full pass found no ear the polygon is self-intersecting, so clip anyway to make
+        // progress
+        if attempts >= len || is_ear(&projected, &remaining, [prev, current, next], tolerance) {
+            triangles.push([prev, current, next]);
+            remaining.remove(i);
+            attempts = 0;
+        } else {
+            i += 1;
+            attempts += 1;
+        }
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+
+    triangles
+}
+
+/// Twice the signed area of the triangle abc, positive when it is counter-clockwise
+fn cross(a: glm::Vec2, b: glm::Vec2, c: glm::Vec2) -> f32 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+/// Whether the corner can be clipped off. Corners with less than `tolerance` of twice their area
+/// count as degenerate
+fn is_ear(
+    projected: &[glm::Vec2],
+    remaining: &[usize],
+    [prev, current, next]: [usize; 3],
+    tolerance: f32,
+) -> bool {
+    let (a, b, c) = (projected[prev], projected[current], projected[next]);
+    if cross(a, b, c) <= tolerance {
+        // Reflex or degenerate corner
+        return false;
+    }
+    remaining
+        .iter()
+        .filter(|&&index| index != prev && index != current && index != next)
+        .map(|&index| projected[index])
+        .filter(|&p| p != a && p != b && p != c)
+        .all(|p| cross(a, b, p) < 0.0 || cross(b, c, p) < 0.0 || cross(c, a, p) < 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concave_polygon_at_any_scale() {
+        let outline = [[0.0, 0.0], [2.0, 0.0], [2.0, 2.0], [1.0, 0.5], [0.0, 2.0]];
+        for scale in [1e4, 1.0, 1e-4] {
+            let points: Vec<VertexPos> = outline
+                .iter()
+                .map(|[x, y]| [x * scale, y * scale, 0.0])
+                .collect();
+            let triangles = triangulate(&points);
+            assert_eq!(triangles.len(), 3);
+            for [a, b, c] in triangles {
+                let corners = [a, b, c].map(|i| glm::vec2(points[i][0], points[i][1]));
+                assert!(
+                    cross(corners[0], corners[1], corners[2]) > 0.0,
+                    "scale {scale}"
+                );
+            }
+        }
+    }
+}
@@ -1,141 +1,734 @@
-use gl33::global_loader::*;
 use gl33::*;
-use std::{fs::File, io::Read};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    f32::consts::PI,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use crate::material_gen::{get_material, Material};
+use crate::asset_path;
+use crate::freeform::{ControlPoint, FreeForm, Tessellation};
+use crate::material_gen::{MapKind, Material, MaterialLibrary};
+use crate::mesh_cache::MeshCache;
+use crate::normal_gen::generate_normals;
+use crate::obj_error::{ObjError, ObjErrorKind};
+use crate::obj_parse::{parse_statements, Corner, Source, Statement};
+use crate::tangent_gen::generate_tangents;
+use crate::texture_cache::TextureCache;
+use crate::triangulate::triangulate;
 
 pub type VertexPos = [f32; 3];
 pub type VertexTex = [f32; 2];
 pub type VertexNormal = [f32; 3];
+pub type VertexColor = [f32; 3];
+/// 1-based `v/vt/vn` indices of a face corner, where 0 means the component was omitted
 pub type Index = [u32; 3];
-pub type Vertex = [f32; 8];
+/// Position, texture coordinate, normal, color, tangent and bitangent
+pub type Vertex = [f32; 17];
 
-/// Gets the vertices of the mesh from the indexed data
-pub fn get_mesh_data(filepath: &str) -> (Vec<Vertex>, Material) {
-    let mut file = File::open(filepath).expect(format!("Couldn't find file {filepath}").as_str());
-    let mut obj = String::default();
-    let _ = file.read_to_string(&mut obj);
-    drop(file);
+/// An index buffer, stored as `u16` when every index fits
+pub enum Indices {
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
 
-    let mut vertex_positions: Vec<VertexPos> = vec![];
-    let mut vertex_uvs: Vec<VertexTex> = vec![];
-    let mut vertex_normals: Vec<VertexNormal> = vec![];
-    let mut face_indices: Vec<Index> = vec![];
-    let mut mtllib: &str = "";
-    let mut material: Option<Material> = None;
-
-    let lines = obj.lines().map(|line| line.trim());
-    for line in lines {
-        let mut split = line.split(" ");
-        match split.next() {
-            Some("mtllib") => {
-                mtllib = split.next().unwrap();
+impl Indices {
+    /// Picks the smallest index type that can address `vertex_count` vertices
+    fn new(indices: Vec<u32>, vertex_count: usize) -> Self {
+        if vertex_count <= u16::MAX as usize + 1 {
+            Indices::U16(indices.into_iter().map(|index| index as u16).collect())
+        } else {
+            Indices::U32(indices)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Indices::U16(indices) => indices.len(),
+            Indices::U32(indices) => indices.len(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> usize {
+        match self {
+            Indices::U16(indices) => indices[index] as usize,
+            Indices::U32(indices) => indices[index] as usize,
+        }
+    }
+
+    pub fn size_of_index(&self) -> usize {
+        match self {
+            Indices::U16(_) => 2,
+            Indices::U32(_) => 4,
+        }
+    }
+}
+
+/// A run of indices drawn with a single material, started by a `usemtl` statement
+pub struct MaterialRange {
+    /// Index into the model's materials
+    pub material: usize,
+    pub indices: Range<usize>,
+}
+
+/// A named part of a model, started by an `o` or `g` statement
+pub struct Submesh {
+    pub name: String,
+    /// The range of the model's triangle indices that belong to this submesh
+    pub indices: Range<usize>,
+    /// The submesh's triangles split up by material, in order
+    pub ranges: Vec<MaterialRange>,
+    /// Two indices per segment, from `l` statements
+    pub lines: Vec<MaterialRange>,
+    /// One index per point, from `p` statements
+    pub points: Vec<MaterialRange>,
+    pub visible: bool,
+}
+
+/// A mesh loaded from an OBJ file, split into the submeshes it declares
+pub struct Model {
+    /// Unique vertices, referenced by `indices`
+    pub vertices: Vec<Vertex>,
+    /// Three indices per triangle, followed by the indices of every line and point
+    pub indices: Indices,
+    pub submeshes: Vec<Submesh>,
+    pub materials: Vec<Material>,
+}
+
+impl Submesh {
+    /// The triangle, line and point ranges, with the primitive each is drawn as
+    pub fn batches(&self) -> [(PrimitiveType, &[MaterialRange]); 3] {
+        [
+            (GL_TRIANGLES, &self.ranges),
+            (GL_LINES, &self.lines),
+            (GL_POINTS, &self.points),
+        ]
+    }
+}
+
+impl Model {
+    /// The center of the bounding box of the vertices a range draws
+    pub fn center(&self, range: &MaterialRange) -> glm::Vec3 {
+        let mut min = glm::Vec3::repeat(f32::INFINITY);
+        let mut max = glm::Vec3::repeat(f32::NEG_INFINITY);
+        for index in range.indices.clone() {
+            let vertex = &self.vertices[self.indices.get(index)];
+            let position = glm::vec3(vertex[0], vertex[1], vertex[2]);
+            min = glm::min2(&min, &position);
+            max = glm::max2(&max, &position);
+        }
+        (min + max) / 2.0
+    }
+}
+
+/// The corners of one kind of primitive, split into material ranges as they're read
+#[derive(Default)]
+struct Batch {
+    corners: Vec<Index>,
+    ranges: Vec<MaterialRange>,
+    range_start: usize,
+    /// Positions in `corners` that refer to tessellated free-form vertices rather than the file's
+    tessellated: Vec<usize>,
+}
+
+impl Batch {
+    /// Ends the current material range, if it has any corners
+    fn close_range(&mut self, material: Option<usize>) {
+        let end = self.corners.len();
+        if let Some(material) = material {
+            if end > self.range_start {
+                self.ranges.push(MaterialRange {
+                    material,
+                    indices: self.range_start..end,
+                });
             }
-            Some("usemtl") => {
-                material = Some(get_material(
-                    format!("material/{mtllib}").as_str(),
-                    split.next().unwrap(),
-                ));
+        }
+        self.range_start = end;
+    }
+}
+
+/// Vertices generated from free-form geometry. They're kept apart from the file's own elements
+/// until the end, so that relative indices in later statements only count those
+#[derive(Default)]
+struct Tessellated {
+    positions: Vec<VertexPos>,
+    colors: Vec<VertexColor>,
+    uvs: Vec<VertexTex>,
+    normals: Vec<VertexNormal>,
+}
+
+/// Returns the current material, creating a default one for elements before the first `usemtl`
+fn current_or_default(
+    current_material: &mut Option<usize>,
+    materials: &mut Vec<Material>,
+) -> usize {
+    *current_material.get_or_insert_with(|| {
+        materials.push(Material::default());
+        materials.len() - 1
+    })
+}
+
+/// Ends the current submesh, if it has anything in it
+fn close_submesh(
+    submeshes: &mut Vec<Submesh>,
+    name: String,
+    start: usize,
+    batches: [&mut Batch; 3],
+    material: Option<usize>,
+) {
+    let [triangles, lines, points] = batches.map(|batch| {
+        batch.close_range(material);
+        std::mem::take(&mut batch.ranges)
+    });
+    if !triangles.is_empty() || !lines.is_empty() || !points.is_empty() {
+        submeshes.push(Submesh {
+            name,
+            indices: start
+                ..start
+                    + triangles
+                        .iter()
+                        .map(|range| range.indices.len())
+                        .sum::<usize>(),
+            ranges: triangles,
+            lines,
+            points,
+            visible: true,
+        });
+    }
+}
+
+/// Resolves the corners of a face, line or point against the elements read so far. Negative
+/// indices count back from the end of their list, and omitted components stay 0
+fn resolve_corners(
+    corners: &[Corner],
+    lengths: [usize; 3],
+    path: &Path,
+    line: usize,
+) -> Result<Vec<Index>, ObjError> {
+    corners
+        .iter()
+        .map(|corner| {
+            let mut index = [0, 0, 0];
+            for i in 0..3 {
+                let resolved = match corner.index[i] {
+                    0 => continue,
+                    x if x > 0 => x,
+                    x => lengths[i] as i64 + 1 + x,
+                };
+                if resolved < 1 || resolved > lengths[i] as i64 {
+                    return Err(ObjError::new(
+                        path,
+                        Some(line),
+                        corner.token,
+                        ObjErrorKind::IndexOutOfRange,
+                    ));
+                }
+                index[i] = resolved as u32;
             }
-            Some("v") => {
-                vertex_positions.push([
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                ]);
+            Ok(index)
+        })
+        .collect()
+}
+
+/// Reports a curve or surface that can't be tessellated. It's left out, but the rest of the file
+/// still loads
+fn warn_skipped(path: &Path, line: usize, keyword: &str, token: &str) {
+    let err = ObjError::new(path, Some(line), token, ObjErrorKind::UnsupportedFreeForm);
+    eprintln!("{err}, skipping this {keyword}");
+}
+
+/// Settings that control how an OBJ file is turned into a model
+pub struct LoadOptions {
+    /// For files without smoothing groups, faces that meet at less than this angle (in radians)
+    /// share generated normals. `None` gives every face flat normals
+    pub crease_angle: Option<f32>,
+    /// Directories to look in for material libraries and textures that aren't found next to the
+    /// file that references them
+    pub search_paths: Vec<PathBuf>,
+    /// Material libraries that are already loaded. A `mtllib` naming the same file uses these
+    /// rather than parsing it again, and libraries loaded for one model are added for the next
+    pub libraries: RefCell<Vec<MaterialLibrary>>,
+    /// Parse large files on several threads
+    pub parallel: bool,
+    /// How many pieces each knot span of a free-form curve or surface is split into
+    pub tessellation: u32,
+    /// Where to keep binary copies of loaded models, so that files which haven't changed since
+    /// aren't parsed again. `None` turns the cache off
+    pub cache_dir: Option<PathBuf>,
+    /// Upload color maps as sRGB, so lighting works on linear values. Off treats them as linear,
+    /// which is how they were drawn before gamma correction
+    pub srgb: bool,
+    /// Textures already uploaded. Every model loaded with these options shares them
+    pub textures: TextureCache,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            crease_angle: Some(PI / 3.0),
+            search_paths: vec![],
+            libraries: RefCell::default(),
+            parallel: true,
+            tessellation: 8,
+            cache_dir: None,
+            srgb: true,
+            textures: TextureCache::default(),
+        }
+    }
+}
+
+/// Gets the vertices of the mesh from the indexed data, or from the cache if the OBJ file and
+/// everything it references are unchanged since it was cached
+pub fn get_mesh_data(filepath: impl AsRef<Path>, options: &LoadOptions) -> Result<Model, ObjError> {
+    let filepath = filepath.as_ref();
+    let cache = options
+        .cache_dir
+        .as_deref()
+        .map(|cache_dir| MeshCache::new(cache_dir, filepath, options));
+
+    let cached = cache
+        .as_ref()
+        .and_then(MeshCache::load)
+        .and_then(|mut model| {
+            // A map that fails to load now counts as a miss, so the full load reports the error
+            for material in &mut model.materials {
+                material.upload(&options.textures, options.srgb).ok()?;
             }
-            Some("vt") => {
-                vertex_uvs.push([
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                ]);
+            Some(model)
+        });
+    let mut model = match cached {
+        Some(model) => model,
+        None => {
+            let (mut model, libraries) = parse_model(filepath, options)?;
+            for material in &mut model.materials {
+                material.upload(&options.textures, options.srgb)?;
             }
-            Some("vn") => {
-                vertex_normals.push([
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                ]);
+            if let Some(cache) = &cache {
+                let mut dependencies = vec![filepath.to_path_buf()];
+                dependencies.extend(libraries);
+                for material in &model.materials {
+                    let desc = &material.desc;
+                    let files = desc.map_files.iter().chain(&desc.cube_files);
+                    dependencies.extend(files.flatten().cloned());
+                }
+                if let Err(err) = cache.store(&model, &dependencies) {
+                    eprintln!("Couldn't cache {}: {err}", filepath.display());
+                }
             }
-            Some("f") => {
-                // Assume face data is clockwise
-                let mut indices = vec![];
-                for triplet in split {
-                    let slashed = triplet.split("/");
-                    let mut triple = [0, 0, 0];
-                    let mut i = 0;
-                    for index in slashed {
-                        if let Ok(parsed) = index.parse::<u32>() {
-                            triple[i] = parsed;
+            model
+        }
+    };
+    fill_missing_maps(&mut model.materials, &options.textures);
+    Ok(model)
+}
+
+/// Parses an OBJ file into a model whose materials aren't uploaded yet. Also returns the material
+/// libraries it listed
+fn parse_model(filepath: &Path, options: &LoadOptions) -> Result<(Model, Vec<PathBuf>), ObjError> {
+    let source = Source::open(filepath)?;
+    let chunks = parse_statements(source.bytes(), filepath, options.parallel);
+
+    let mut vertex_positions: Vec<VertexPos> = vec![];
+    // Parallel to the positions, white unless the `v` line gave a color
+    let mut vertex_colors: Vec<VertexColor> = vec![];
+    let mut vertex_weights: Vec<f32> = vec![];
+    let mut vertex_uvs: Vec<VertexTex> = vec![];
+    let mut vertex_normals: Vec<VertexNormal> = vec![];
+    let mut faces = Batch::default();
+    let mut segments = Batch::default();
+    let mut points = Batch::default();
+    let mut mtllibs: Vec<PathBuf> = vec![];
+    let mut materials: Vec<Material> = vec![];
+    // Materials already loaded from the library, so repeated `usemtl` statements reuse them
+    let mut material_indices: HashMap<String, usize> = HashMap::new();
+    let mut current_material: Option<usize> = None;
+    // The smoothing group of each triangle, where 0 means smoothing is off
+    let mut smoothing_groups: Vec<u32> = vec![];
+    let mut smoothing_group = 0;
+    let mut has_smoothing_groups = false;
+    let mut submeshes: Vec<Submesh> = vec![];
+    // Faces before the first `o` or `g` statement go into a default submesh
+    let mut submesh_name = String::from("default");
+    let mut submesh_start = 0;
+    // `cstype` and `deg` carry over from one free-form curve or surface to the next
+    let mut freeform_type = None;
+    let mut degree = None;
+    // The curve or surface being read, with its line number and statement
+    let mut freeform: Option<(usize, &str, FreeForm)> = None;
+    let mut tessellated = Tessellated::default();
+
+    for chunk in chunks {
+        for (line_number, statement) in chunk.statements {
+            match statement {
+                Statement::Mtllib(rest) => {
+                    // Several libraries may be listed, but a single file name may also contain
+                    // spaces
+                    let whole = asset_path::resolve(rest, filepath, &options.search_paths);
+                    if whole.is_file() {
+                        mtllibs.push(whole);
+                    } else {
+                        mtllibs.extend(rest.split_whitespace().map(|name| {
+                            asset_path::resolve(name, filepath, &options.search_paths)
+                        }));
+                    }
+                }
+                Statement::Usemtl(name) => {
+                    for batch in [&mut faces, &mut segments, &mut points] {
+                        batch.close_range(current_material);
+                    }
+                    current_material = Some(match material_indices.get(name) {
+                        Some(&index) => index,
+                        None => {
+                            // The first library that defines the material wins
+                            let mut material = None;
+                            let mut libraries = options.libraries.borrow_mut();
+                            for mtllib in &mtllibs {
+                                let loaded = libraries.iter().position(|library| {
+                                    asset_path::same_file(library.path(), mtllib)
+                                });
+                                let library = match loaded {
+                                    Some(index) => &libraries[index],
+                                    None => {
+                                        libraries.push(MaterialLibrary::load(
+                                            mtllib,
+                                            &options.search_paths,
+                                        )?);
+                                        &libraries[libraries.len() - 1]
+                                    }
+                                };
+                                material = library.get(name).cloned();
+                                if material.is_some() {
+                                    break;
+                                }
+                            }
+                            materials.push(Material::new(material.unwrap_or_default()));
+                            material_indices.insert(name.to_string(), materials.len() - 1);
+                            materials.len() - 1
                         }
-                        i += 1;
+                    });
+                }
+                Statement::Group(names) => {
+                    close_submesh(
+                        &mut submeshes,
+                        submesh_name,
+                        submesh_start,
+                        [&mut faces, &mut segments, &mut points],
+                        current_material,
+                    );
+                    // Groups may have several names, which together identify the group
+                    submesh_name = names.split_whitespace().collect::<Vec<_>>().join(" ");
+                    submesh_start = faces.corners.len();
+                }
+                Statement::Smooth(group) => {
+                    smoothing_group = group;
+                    has_smoothing_groups = true;
+                }
+                Statement::Position(position, weight, color) => {
+                    vertex_positions.push(position);
+                    vertex_weights.push(weight);
+                    vertex_colors.push(color.unwrap_or([1.0, 1.0, 1.0]));
+                }
+                Statement::Uv(uv) => vertex_uvs.push(uv),
+                Statement::Normal(normal) => vertex_normals.push(normal),
+                Statement::Face(corners) => {
+                    // Assume face data is clockwise
+                    let lengths = [
+                        vertex_positions.len(),
+                        vertex_uvs.len(),
+                        vertex_normals.len(),
+                    ];
+                    let indices = resolve_corners(&corners, lengths, filepath, line_number)?;
+                    current_or_default(&mut current_material, &mut materials);
+                    let positions: Vec<VertexPos> = indices
+                        .iter()
+                        .map(|index| vertex_positions[index[0] as usize - 1])
+                        .collect();
+                    for triangle in triangulate(&positions) {
+                        for corner in triangle {
+                            faces.corners.push(indices[corner]);
+                        }
+                        smoothing_groups.push(smoothing_group);
                     }
-                    indices.push(triple);
                 }
-                match indices.len() {
-                    x if x < 3 => panic!(),
-                    3 => {
-                        for index in indices {
-                            face_indices.push(index);
+                Statement::Line(ref corners) | Statement::Point(ref corners) => {
+                    let is_line = matches!(statement, Statement::Line(_));
+                    let lengths = [
+                        vertex_positions.len(),
+                        vertex_uvs.len(),
+                        vertex_normals.len(),
+                    ];
+                    let indices = resolve_corners(corners, lengths, filepath, line_number)?;
+                    current_or_default(&mut current_material, &mut materials);
+                    if is_line {
+                        // Split the polyline into separate segments for GL_LINES
+                        for segment in indices.windows(2) {
+                            segments.corners.extend_from_slice(segment);
                         }
+                    } else {
+                        points.corners.extend(indices);
                     }
-                    4 => {
-                        face_indices.push(indices[0]);
-                        face_indices.push(indices[1]);
-                        face_indices.push(indices[2]);
-                        face_indices.push(indices[2]);
-                        face_indices.push(indices[3]);
-                        face_indices.push(indices[0]);
+                }
+                Statement::Cstype(basis, rational) => freeform_type = Some((basis, rational)),
+                Statement::Degree(value) => degree = Some(value),
+                Statement::Curve(_, ref corners) | Statement::Surface(_, ref corners) => {
+                    let (keyword, ranges) = match statement {
+                        Statement::Surface(ranges, _) => ("surf", ranges.to_vec()),
+                        Statement::Curve(range, _) => ("curv", vec![range]),
+                        _ => unreachable!(),
+                    };
+                    if let Some((line, keyword, _)) = freeform {
+                        return Err(ObjError::new(
+                            filepath,
+                            Some(line),
+                            keyword,
+                            ObjErrorKind::UnterminatedFreeForm,
+                        ));
                     }
-                    x if x > 4 => {
-                        todo!();
+                    let missing = |statement| {
+                        ObjError::new(
+                            filepath,
+                            Some(line_number),
+                            statement,
+                            ObjErrorKind::MissingValue,
+                        )
+                    };
+                    let (basis, rational) = freeform_type.ok_or_else(|| missing("cstype"))?;
+                    let basis = match basis {
+                        Ok(basis) => basis,
+                        Err(name) => {
+                            // Without a `freeform`, everything up to the `end` is ignored
+                            warn_skipped(filepath, line_number, keyword, name);
+                            continue;
+                        }
+                    };
+                    let degree = degree.ok_or_else(|| missing("deg"))?;
+                    let lengths = [
+                        vertex_positions.len(),
+                        vertex_uvs.len(),
+                        vertex_normals.len(),
+                    ];
+                    let points = resolve_corners(corners, lengths, filepath, line_number)?
+                        .into_iter()
+                        .map(|index| {
+                            let position = index[0] as usize - 1;
+                            ControlPoint {
+                                position: vertex_positions[position],
+                                weight: vertex_weights[position],
+                                color: vertex_colors[position],
+                                uv: match index[1] {
+                                    0 => None,
+                                    i => Some(vertex_uvs[i as usize - 1]),
+                                },
+                            }
+                        })
+                        .collect();
+                    freeform = Some((
+                        line_number,
+                        keyword,
+                        FreeForm {
+                            basis,
+                            rational,
+                            degree,
+                            ranges,
+                            points,
+                            parms: Default::default(),
+                        },
+                    ));
+                }
+                Statement::Parm(Ok(axis), values) => {
+                    if let Some((_, _, freeform)) = &mut freeform {
+                        freeform.parms[axis] = values;
+                    }
+                }
+                Statement::Parm(Err(direction), _) => {
+                    if let Some((line, keyword, _)) = freeform.take() {
+                        warn_skipped(filepath, line, keyword, direction);
+                    }
+                }
+                Statement::End => {
+                    let Some((line, keyword, freeform)) = freeform.take() else {
+                        continue;
+                    };
+                    let tessellation =
+                        freeform.tessellate(options.tessellation).ok_or_else(|| {
+                            ObjError::new(filepath, Some(line), keyword, ObjErrorKind::InvalidKnots)
+                        })?;
+                    current_or_default(&mut current_material, &mut materials);
+                    // Tessellated indices are 1-based like the file's, and get offset past them
+                    // once the whole file has been read
+                    let first = tessellated.positions.len() as u32 + 1;
+                    match tessellation {
+                        Tessellation::Curve(curve) => {
+                            for (position, color) in curve {
+                                tessellated.positions.push(position);
+                                tessellated.colors.push(color);
+                            }
+                            let count = tessellated.positions.len() as u32 + 1 - first;
+                            for i in 0..count.saturating_sub(1) {
+                                for corner in [first + i, first + i + 1] {
+                                    segments.tessellated.push(segments.corners.len());
+                                    segments.corners.push([corner, 0, 0]);
+                                }
+                            }
+                        }
+                        Tessellation::Surface(vertices, triangles) => {
+                            // Only surfaces have texture coordinates and normals, so those two
+                            // share indices
+                            let first_attribute = tessellated.uvs.len() as u32 + 1;
+                            // Degenerate points get a generated normal instead
+                            let has_normal: Vec<bool> = vertices
+                                .iter()
+                                .map(|vertex| vertex.normal != [0.0, 0.0, 0.0])
+                                .collect();
+                            for vertex in vertices {
+                                tessellated.positions.push(vertex.position);
+                                tessellated.colors.push(vertex.color);
+                                tessellated.uvs.push(vertex.uv);
+                                tessellated.normals.push(vertex.normal);
+                            }
+                            for triangle in triangles {
+                                for corner in triangle {
+                                    let attribute = first_attribute + corner as u32;
+                                    let normal = if has_normal[corner] { attribute } else { 0 };
+                                    faces.tessellated.push(faces.corners.len());
+                                    faces
+                                        .corners
+                                        .push([first + corner as u32, attribute, normal]);
+                                }
+                                smoothing_groups.push(smoothing_group);
+                            }
+                        }
                     }
-                    _ => unreachable!(),
                 }
             }
-            _ => {}
+        }
+        // Everything before the error has been handled, so errors come out in file order
+        if let Some(err) = chunk.error {
+            return Err(err);
+        }
+    }
+    if let Some((line, keyword, _)) = freeform {
+        return Err(ObjError::new(
+            filepath,
+            Some(line),
+            keyword,
+            ObjErrorKind::UnterminatedFreeForm,
+        ));
+    }
+    close_submesh(
+        &mut submeshes,
+        submesh_name,
+        submesh_start,
+        [&mut faces, &mut segments, &mut points],
+        current_material,
+    );
+
+    // Tessellated vertices go after the file's own, now that their final indices are known
+    let offsets = [
+        vertex_positions.len(),
+        vertex_uvs.len(),
+        vertex_normals.len(),
+    ]
+    .map(|len| len as u32);
+    vertex_positions.extend(tessellated.positions);
+    vertex_colors.extend(tessellated.colors);
+    vertex_uvs.extend(tessellated.uvs);
+    vertex_normals.extend(tessellated.normals);
+    for batch in [&mut faces, &mut segments] {
+        for &corner in &batch.tessellated {
+            for (index, offset) in batch.corners[corner].iter_mut().zip(offsets) {
+                if *index != 0 {
+                    *index += offset;
+                }
+            }
         }
     }
 
+    // Only generate normals if some corner is missing one
+    let generated_normals = if faces.corners.iter().any(|index| index[2] == 0) {
+        generate_normals(
+            &vertex_positions,
+            &faces.corners,
+            &smoothing_groups,
+            has_smoothing_groups,
+            options.crease_angle,
+        )
+    } else {
+        vec![]
+    };
+
+    // Corners that end up with identical attributes share a single vertex. Keying on the final
+    // attribute bits rather than the v/vt/vn triplet also merges corners whose normals were
+    // generated to the same value. Tangents are derived from the other attributes afterwards, so
+    // they aren't part of the key
     let mut vertices: Vec<Vertex> = vec![];
-    for index in face_indices {
+    let corner_count = faces.corners.len() + segments.corners.len() + points.corners.len();
+    let mut indices: Vec<u32> = Vec::with_capacity(corner_count);
+    let mut unique: HashMap<[u32; 11], u32> = HashMap::new();
+    let corners = faces
+        .corners
+        .iter()
+        .chain(&segments.corners)
+        .chain(&points.corners);
+    for (i, index) in corners.enumerate() {
         let pos = vertex_positions[index[0] as usize - 1];
-        let tex = vertex_uvs[index[1] as usize - 1];
-        let normal = vertex_normals[index[2] as usize - 1];
+        let color = vertex_colors[index[0] as usize - 1];
+        let tex = match index[1] {
+            0 => [0.0, 0.0],
+            i => vertex_uvs[i as usize - 1],
+        };
+        // Lines and points aren't lit, so they don't need a real normal
+        let normal = match index[2] {
+            0 => generated_normals.get(i).copied().unwrap_or_default(),
+            i => vertex_normals[i as usize - 1],
+        };
 
-        vertices.push([
-            pos[0], pos[1], pos[2], tex[0], tex[1], normal[0], normal[1], normal[2],
-        ]);
+        let attributes = [
+            pos[0], pos[1], pos[2], tex[0], tex[1], normal[0], normal[1], normal[2], color[0],
+            color[1], color[2],
+        ];
+        let vertex_index = *unique
+            .entry(attributes.map(f32::to_bits))
+            .or_insert_with(|| {
+                let mut vertex = Vertex::default();
+                vertex[..11].copy_from_slice(&attributes);
+                vertices.push(vertex);
+                (vertices.len() - 1) as u32
+            });
+        indices.push(vertex_index);
     }
+    generate_tangents(&mut vertices, &indices[..faces.corners.len()]);
+    let indices = Indices::new(indices, vertices.len());
 
-    let mut material = material.unwrap_or(Material::default());
-    if material.ambient_map == 0 || material.diffuse_map == 0 || material.specular_map == 0 {
-        unsafe {
-            let mut white: u32 = 0;
-            glGenTextures(1, &mut white);
-            glBindTexture(GL_TEXTURE_2D, white);
-            glTexImage2D(
-                GL_TEXTURE_2D,
-                0,
-                GL_RGB.0 as _,
-                1,
-                1,
-                0,
-                GL_RGB,
-                GL_UNSIGNED_BYTE,
-                [255u8, 255u8, 255u8].as_ptr() as _,
-            );
-            if material.ambient_map == 0 {
-                material.ambient_map = white;
-            }
-            if material.diffuse_map == 0 {
-                material.diffuse_map = white;
-            }
-            if material.specular_map == 0 {
-                material.specular_map = white;
-            }
+    // Lines and points come after every triangle in the index buffer
+    let line_offset = faces.corners.len();
+    let point_offset = line_offset + segments.corners.len();
+    for submesh in &mut submeshes {
+        for range in &mut submesh.lines {
+            range.indices = range.indices.start + line_offset..range.indices.end + line_offset;
+        }
+        for range in &mut submesh.points {
+            range.indices = range.indices.start + point_offset..range.indices.end + point_offset;
         }
     }
 
-    (vertices, material)
+    // Every face corner becomes exactly one index, so the submesh ranges carry over as is
+    let model = Model {
+        vertices,
+        indices,
+        submeshes,
+        materials,
+    };
+    Ok((model, mtllibs))
+}
+
+/// Points every map that wasn't loaded at the shared white texture, except those that are
+/// switched off when missing
+fn fill_missing_maps(materials: &mut [Material], textures: &TextureCache) {
+    for material in materials {
+        for kind in MapKind::ALL.into_iter().filter(|kind| kind.has_fallback()) {
+            material.maps[kind as usize].get_or_insert_with(|| textures.white());
+        }
+    }
 }